@@ -1,16 +1,28 @@
-use crate::CHUNK_SIZE;
+use crate::chunker::{Chunker, DEFAULT_CHUNK_SIZE};
+use anyhow::Context;
 use bincode::{Decode, Encode};
 use chrono::{DateTime, Utc};
+use std::{fmt, str::FromStr};
 
 #[derive(Encode, Clone, Decode, Debug, PartialEq)]
 pub struct Blob {
-    chunk_hashes: Vec<[u8; 32]>,
+    chunks: Vec<ChunkEntry>,
+    // exact length of the source in bytes, sum of the chunk lengths
+    size: u64,
     timestamp: i64,
 }
 
+#[derive(Encode, Clone, Copy, Decode, Debug, PartialEq, Eq)]
+struct ChunkEntry {
+    hash: [u8; 32],
+    len: u32,
+}
+
 // Used to store all version info for a backed up file
 #[derive(Encode, Clone, Decode, Debug)]
 pub struct Document {
+    // how the source was split, fixed for the lifetime of the document
+    chunker: Chunker,
     current: Blob,
     history: Vec<PrevBlob>,
 }
@@ -18,18 +30,197 @@ pub struct Document {
 // Stores differences between consecutive versions
 #[derive(Encode, Clone, Decode, Debug)]
 pub struct PrevBlob {
+    same_chunks_lengths: Vec<usize>,
+    diff_chunks: Vec<ChunkEntry>,
+    size: u64,
+    timestamp: i64,
+}
+
+// Encoded documents start with this tag and a format version. A legacy document starts
+// with the bincode length of its chunk list, which is never 0xff.
+const DOCUMENT_TAG: u8 = 0xff;
+const DOCUMENT_VERSION: u8 = 2;
+
+// Layout written before chunk lengths, sizes and the chunker were recorded, all chunks
+// were `DEFAULT_CHUNK_SIZE` long then
+#[derive(Decode)]
+struct LegacyDocument {
+    current: LegacyBlob,
+    history: Vec<LegacyPrevBlob>,
+}
+
+#[derive(Decode)]
+struct LegacyBlob {
+    chunk_hashes: Vec<[u8; 32]>,
+    timestamp: i64,
+}
+
+#[derive(Decode)]
+struct LegacyPrevBlob {
     same_chunks_lengths: Vec<usize>,
     diff_chunks: Vec<[u8; 32]>,
     timestamp: i64,
 }
 
+fn legacy_chunks(hashes: Vec<[u8; 32]>) -> Vec<ChunkEntry> {
+    hashes
+        .into_iter()
+        .map(|hash| ChunkEntry {
+            hash,
+            len: DEFAULT_CHUNK_SIZE,
+        })
+        .collect()
+}
+
+impl From<LegacyDocument> for Document {
+    fn from(legacy: LegacyDocument) -> Self {
+        let chunk_size = u64::from(DEFAULT_CHUNK_SIZE);
+        let current = Blob {
+            size: legacy.current.chunk_hashes.len() as u64 * chunk_size,
+            chunks: legacy_chunks(legacy.current.chunk_hashes),
+            timestamp: legacy.current.timestamp,
+        };
+        let history = legacy
+            .history
+            .into_iter()
+            .map(|prev| {
+                // every run of same chunks is followed by at most one different chunk
+                let same: usize = prev.same_chunks_lengths.iter().sum();
+                let count = same + prev.diff_chunks.len();
+                PrevBlob {
+                    same_chunks_lengths: prev.same_chunks_lengths,
+                    diff_chunks: legacy_chunks(prev.diff_chunks),
+                    size: count as u64 * chunk_size,
+                    timestamp: prev.timestamp,
+                }
+            })
+            .collect();
+        Self {
+            chunker: Chunker::DEFAULT,
+            current,
+            history,
+        }
+    }
+}
+
+// Version 1 recorded the exact size, chunks were `DEFAULT_CHUNK_SIZE` long except for the
+// last one
+#[derive(Decode)]
+struct DocumentV1 {
+    current: BlobV1,
+    history: Vec<PrevBlobV1>,
+}
+
+#[derive(Decode)]
+struct BlobV1 {
+    chunk_hashes: Vec<[u8; 32]>,
+    size: u64,
+    timestamp: i64,
+}
+
+#[derive(Decode)]
+struct PrevBlobV1 {
+    same_chunks_lengths: Vec<usize>,
+    diff_chunks: Vec<[u8; 32]>,
+    size: u64,
+    timestamp: i64,
+}
+
+// Entry for chunk `index` of a fixed size split of `size` bytes
+fn v1_chunk(hash: [u8; 32], index: usize, size: u64) -> ChunkEntry {
+    let chunk_size = u64::from(DEFAULT_CHUNK_SIZE);
+    let rest = size.saturating_sub(index as u64 * chunk_size);
+    ChunkEntry {
+        hash,
+        len: rest.min(chunk_size) as u32,
+    }
+}
+
+impl From<DocumentV1> for Document {
+    fn from(v1: DocumentV1) -> Self {
+        let current = Blob {
+            chunks: v1
+                .current
+                .chunk_hashes
+                .into_iter()
+                .enumerate()
+                .map(|(index, hash)| v1_chunk(hash, index, v1.current.size))
+                .collect(),
+            size: v1.current.size,
+            timestamp: v1.current.timestamp,
+        };
+        let history = v1
+            .history
+            .into_iter()
+            .map(|prev| {
+                // the different chunk follows its run of same chunks
+                let mut index = 0;
+                let mut diff_chunks = Vec::with_capacity(prev.diff_chunks.len());
+                let mut diffs = prev.diff_chunks.into_iter();
+                for same_len in &prev.same_chunks_lengths {
+                    index += same_len;
+                    if let Some(hash) = diffs.next() {
+                        diff_chunks.push(v1_chunk(hash, index, prev.size));
+                        index += 1;
+                    }
+                }
+                PrevBlob {
+                    same_chunks_lengths: prev.same_chunks_lengths,
+                    diff_chunks,
+                    size: prev.size,
+                    timestamp: prev.timestamp,
+                }
+            })
+            .collect();
+        Self {
+            chunker: Chunker::DEFAULT,
+            current,
+            history,
+        }
+    }
+}
+
 impl Document {
-    pub fn new(blob: Blob) -> Self {
+    pub fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        let mut bytes = vec![DOCUMENT_TAG, DOCUMENT_VERSION];
+        bincode::encode_into_std_write(self, &mut bytes, bincode::config::standard())?;
+        Ok(bytes)
+    }
+    pub fn decode(bytes: &[u8]) -> anyhow::Result<Self> {
+        let config = bincode::config::standard();
+        match bytes {
+            [DOCUMENT_TAG, DOCUMENT_VERSION, rest @ ..] => {
+                Ok(bincode::decode_from_slice(rest, config)?.0)
+            }
+            [DOCUMENT_TAG, 1, rest @ ..] => {
+                let v1: DocumentV1 = bincode::decode_from_slice(rest, config)?.0;
+                Ok(v1.into())
+            }
+            [DOCUMENT_TAG, version, ..] => {
+                anyhow::bail!("unknown root document version {version}")
+            }
+            _ => {
+                let legacy: LegacyDocument = bincode::decode_from_slice(bytes, config)?.0;
+                Ok(legacy.into())
+            }
+        }
+    }
+    pub fn new(blob: Blob, chunker: Chunker) -> Self {
         Self {
+            chunker,
             current: blob,
             history: Vec::new(),
         }
     }
+    pub fn chunker(&self) -> Chunker {
+        self.chunker
+    }
+    // Cheap consistency check of the chunking parameters and the current version
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.chunker.validate()?;
+        self.current.check_invariants()?;
+        self.current.check_chunking(&self.chunker)
+    }
     pub fn current(&self) -> &Blob {
         &self.current
     }
@@ -43,13 +234,196 @@ impl Document {
     pub fn versions(&self) -> impl Iterator<Item = &PrevBlob> + '_ {
         self.history.iter().rev()
     }
+    // Number of stored versions, including the current one
+    pub fn version_count(&self) -> usize {
+        self.history.len() + 1
+    }
+    // Timestamps of all versions, oldest first
+    pub fn version_timestamps(&self) -> impl Iterator<Item = DateTime<Utc>> + '_ {
+        self.history
+            .iter()
+            .map(|x| x.timestamp())
+            .chain([self.current.timestamp()])
+    }
+    // Drop old versions for which `keep` returns false, the current version is always kept.
+    // Diffs of the remaining versions are recomputed against the next newer kept version.
+    pub fn retain_versions(&mut self, mut keep: impl FnMut(usize) -> bool) {
+        let mut next_kept = self.current.clone();
+        let mut blob = self.current.clone();
+        let mut history = Vec::new();
+        for (n, prev) in self.history.iter().enumerate().rev() {
+            blob = prev.compute(&blob);
+            if keep(n) {
+                history.push(PrevBlob::from_diff(&next_kept, &blob));
+                next_kept = blob.clone();
+            }
+        }
+        history.reverse();
+        self.history = history;
+    }
+    // Reconstruct version `n`, 0 is the oldest and `version_count() - 1` is the current one
+    pub fn version(&self, n: usize) -> Option<Blob> {
+        if n >= self.version_count() {
+            return None;
+        }
+        // fold the reverse diffs from newest to oldest
+        let blob = self.history[n..]
+            .iter()
+            .rev()
+            .fold(self.current.clone(), |next, prev| prev.compute(&next));
+        Some(blob)
+    }
+    // Reconstruct the newest version taken at or before `time`
+    pub fn version_at(&self, time: DateTime<Utc>) -> Option<Blob> {
+        self.version(self.version_number_at(time)?)
+    }
+    fn version_number_at(&self, time: DateTime<Utc>) -> Option<usize> {
+        let time = time.timestamp();
+        if self.current.timestamp <= time {
+            Some(self.history.len())
+        } else {
+            self.history.iter().rposition(|x| x.timestamp <= time)
+        }
+    }
+    // Version number selected by `spec`, if it exists
+    pub fn resolve(&self, spec: &VersionSpec) -> Option<usize> {
+        match *spec {
+            VersionSpec::Number(n) => (n < self.version_count()).then_some(n),
+            VersionSpec::Timestamp(time) => self.version_number_at(time),
+            VersionSpec::Latest(k) => self.version_count().checked_sub(k + 1),
+        }
+    }
+    pub fn select(&self, spec: &VersionSpec) -> Option<Blob> {
+        self.version(self.resolve(spec)?)
+    }
+    // Remove version `n`, re-linking the diff of the next older version to the next newer one.
+    // Every remaining version is checked to reconstruct exactly as before.
+    pub fn forget(&mut self, n: usize) -> anyhow::Result<()> {
+        let count = self.version_count();
+        anyhow::ensure!(n < count, "version {n} not found");
+        anyhow::ensure!(count > 1, "cannot forget the only version");
+        let mut expected = self.version_digests();
+        expected.remove(n);
+
+        let mut doc = self.clone();
+        if n == self.history.len() {
+            // the previous version becomes current
+            let prev = doc.history.pop().unwrap();
+            doc.current = prev.compute(&doc.current);
+        } else {
+            doc.retain_versions(|i| i != n);
+        }
+        anyhow::ensure!(
+            doc.version_digests() == expected,
+            "remaining versions don't reconstruct after forgetting version {n}"
+        );
+        *self = doc;
+        Ok(())
+    }
+    // Reconstruct every version, newest first, checking that each one is well formed
+    pub fn verify_versions(&self, mut visit: impl FnMut(usize, &Blob)) -> anyhow::Result<()> {
+        let mut blob = self.current.clone();
+        let mut n = self.history.len();
+        loop {
+            blob.check_invariants()
+                .and_then(|()| blob.check_chunking(&self.chunker))
+                .with_context(|| format!("version {n} is corrupt"))?;
+            visit(n, &blob);
+            if n == 0 {
+                return Ok(());
+            }
+            n -= 1;
+            blob = self.history[n]
+                .try_compute(&blob)
+                .with_context(|| format!("version {n} doesn't reconstruct"))?;
+        }
+    }
+    // Digest of every version, oldest first
+    fn version_digests(&self) -> Vec<blake3::Hash> {
+        let mut blob = self.current.clone();
+        let mut digests = vec![blob.digest()];
+        for prev in self.history.iter().rev() {
+            blob = prev.compute(&blob);
+            digests.push(blob.digest());
+        }
+        digests.reverse();
+        digests
+    }
+}
+
+// Selects one version of a document
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VersionSpec {
+    // absolute version number, 0 is the oldest
+    Number(usize),
+    // newest version taken at or before the time
+    Timestamp(DateTime<Utc>),
+    // `latest~k`, k versions before the current one
+    Latest(usize),
+}
+
+impl VersionSpec {
+    pub const LATEST: Self = Self::Latest(0);
+}
+
+impl Default for VersionSpec {
+    fn default() -> Self {
+        Self::LATEST
+    }
 }
 
-const FAKE_HASH: [u8; 32] = [0; 32];
+impl FromStr for VersionSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if s == "latest" {
+            return Ok(Self::LATEST);
+        }
+        if let Some(k) = s.strip_prefix("latest~") {
+            return Ok(Self::Latest(k.parse()?));
+        }
+        if let Ok(n) = s.parse() {
+            return Ok(Self::Number(n));
+        }
+        match DateTime::parse_from_rfc3339(s) {
+            Ok(time) => Ok(Self::Timestamp(time.to_utc())),
+            Err(_) => anyhow::bail!(
+                "invalid version {s:?}, expected a number, an RFC 3339 timestamp or latest~k"
+            ),
+        }
+    }
+}
+
+impl fmt::Display for VersionSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Number(n) => write!(f, "{n}"),
+            Self::Timestamp(time) => write!(f, "{}", time.to_rfc3339()),
+            Self::Latest(0) => write!(f, "latest"),
+            Self::Latest(k) => write!(f, "latest~{k}"),
+        }
+    }
+}
+
+// Recorded instead of the hash of an all-zero chunk. Such chunks are never uploaded, no
+// chunk id is all zeros.
+pub const ZERO_CHUNK: blake3::Hash = blake3::Hash::from_bytes([0; 32]);
+
+pub fn is_zero_chunk(data: &[u8]) -> bool {
+    // or-ing blocks vectorizes, unlike a byte-wise `all`
+    data.chunks(64)
+        .all(|x| x.iter().fold(0, |acc, &b| acc | b) == 0)
+}
+
+const FAKE_CHUNK: ChunkEntry = ChunkEntry {
+    hash: [0; 32],
+    len: 0,
+};
 impl Blob {
     pub fn empty() -> Self {
         Self {
-            chunk_hashes: Vec::new(),
+            chunks: Vec::new(),
+            size: 0,
             timestamp: chrono::Utc::now().timestamp(),
         }
     }
@@ -57,21 +431,69 @@ impl Blob {
         DateTime::from_timestamp(self.timestamp, 0).unwrap()
     }
     pub fn size(&self) -> u64 {
-        self.chunk_hashes.len() as u64 * CHUNK_SIZE as u64
+        self.size
     }
-    pub fn set(&mut self, idx: usize, hash: blake3::Hash) {
-        if self.chunk_hashes.len() <= idx {
-            self.chunk_hashes.resize(idx + 1, FAKE_HASH);
+    pub fn set_size(&mut self, size: u64) {
+        self.size = size;
+    }
+    pub fn set(&mut self, idx: usize, hash: blake3::Hash, len: usize) {
+        if self.chunks.len() <= idx {
+            self.chunks.resize(idx + 1, FAKE_CHUNK);
         }
-        self.chunk_hashes[idx] = hash.into();
+        self.chunks[idx] = ChunkEntry {
+            hash: hash.into(),
+            len: len.try_into().expect("chunk too large"),
+        };
+    }
+    // The first `count` chunks, with the size they cover
+    pub fn prefix(&self, count: usize) -> Blob {
+        let chunks = self.chunks[..count].to_vec();
+        Blob {
+            size: chunks.iter().map(|x| u64::from(x.len)).sum(),
+            chunks,
+            timestamp: self.timestamp,
+        }
+    }
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
     }
+    // Hashes of the chunks stored in the repository, zero chunks are left out
     pub fn chunk_hashes(&self) -> impl Iterator<Item = blake3::Hash> + '_ {
-        self.chunk_hashes
+        self.chunks
             .iter()
-            .map(|x| blake3::Hash::from_bytes(*x))
+            .map(|x| blake3::Hash::from_bytes(x.hash))
+            .filter(|x| *x != ZERO_CHUNK)
+    }
+    fn digest(&self) -> blake3::Hash {
+        let bytes = bincode::encode_to_vec(self, bincode::config::standard()).unwrap();
+        blake3::hash(&bytes)
+    }
+    // Chunk hashes with the length of each chunk, including `ZERO_CHUNK` markers
+    pub fn chunks(&self) -> impl Iterator<Item = (blake3::Hash, u64)> + '_ {
+        self.chunks
+            .iter()
+            .map(|x| (blake3::Hash::from_bytes(x.hash), x.len.into()))
     }
     pub fn verify_invariants(&self) {
-        assert!(self.chunk_hashes.iter().all(|x| x != &FAKE_HASH));
+        if let Err(e) = self.check_invariants() {
+            panic!("{e}");
+        }
+    }
+    pub fn check_chunking(&self, chunker: &Chunker) -> anyhow::Result<()> {
+        chunker.check_lengths(self.chunks.iter().map(|x| x.len.into()))
+    }
+    pub fn check_invariants(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.chunks.iter().all(|x| x.len > 0),
+            "blob has unset or empty chunks"
+        );
+        let total = self.chunks.iter().map(|x| u64::from(x.len)).sum::<u64>();
+        anyhow::ensure!(
+            total == self.size,
+            "size {} doesn't match chunk lengths {total}",
+            self.size,
+        );
+        Ok(())
     }
 }
 
@@ -83,13 +505,13 @@ impl PrevBlob {
 
         let mut current_same_run = 0;
 
-        for i in 0..prev.chunk_hashes.len() {
-            if i < current.chunk_hashes.len() && current.chunk_hashes[i] == prev.chunk_hashes[i] {
+        for i in 0..prev.chunks.len() {
+            if i < current.chunks.len() && current.chunks[i] == prev.chunks[i] {
                 current_same_run += 1;
             } else {
                 same_chunks_lengths.push(current_same_run);
                 current_same_run = 0;
-                diff_chunks.push(prev.chunk_hashes[i]);
+                diff_chunks.push(prev.chunks[i]);
             }
         }
 
@@ -100,6 +522,7 @@ impl PrevBlob {
         let result = Self {
             same_chunks_lengths,
             diff_chunks,
+            size: prev.size,
             timestamp: prev.timestamp,
         };
 
@@ -116,30 +539,42 @@ impl PrevBlob {
 
     // Reconstruct a full blob from a diff and next version
     fn compute(&self, next_version: &Blob) -> Blob {
-        let mut next_chunks = next_version.chunk_hashes.iter();
+        self.try_compute(next_version)
+            .expect("diff refers past the end of the next version")
+    }
+
+    // Like `compute`, but returns `None` if the diff doesn't fit `next_version`
+    fn try_compute(&self, next_version: &Blob) -> Option<Blob> {
+        let mut next_chunks = next_version.chunks.iter();
         let mut diff_chunks = self.diff_chunks.iter();
-        let mut chunks_hashes = Vec::new();
+        let mut chunks = Vec::new();
 
         for same_len in &self.same_chunks_lengths {
             // Copy same chunks from next version
             for _ in 0..*same_len {
-                chunks_hashes.push(next_chunks.next().unwrap().clone());
+                chunks.push(*next_chunks.next()?);
             }
 
             // Add one different chunk
             if let Some(diff_chunk) = diff_chunks.next() {
                 let _ = next_chunks.next();
-                chunks_hashes.push(diff_chunk.clone());
+                chunks.push(*diff_chunk);
             }
         }
 
-        Blob {
-            chunk_hashes: chunks_hashes,
+        Some(Blob {
+            chunks,
+            size: self.size,
             timestamp: self.timestamp,
-        }
+        })
     }
     pub fn retained_size(&self) -> u64 {
-        self.diff_chunks.len() as u64 * CHUNK_SIZE as u64
+        // zero chunks take no space in the repository
+        self.diff_chunks
+            .iter()
+            .filter(|x| blake3::Hash::from_bytes(x.hash) != ZERO_CHUNK)
+            .map(|x| u64::from(x.len))
+            .sum()
     }
     pub fn timestamp(&self) -> DateTime<Utc> {
         DateTime::from_timestamp(self.timestamp, 0).unwrap()
@@ -147,6 +582,7 @@ impl PrevBlob {
     pub fn unique_chunk_hashes(&self) -> impl Iterator<Item = blake3::Hash> + '_ {
         self.diff_chunks
             .iter()
-            .map(|x| blake3::Hash::from_bytes(*x))
+            .map(|x| blake3::Hash::from_bytes(x.hash))
+            .filter(|x| *x != ZERO_CHUNK)
     }
 }
@@ -0,0 +1,128 @@
+use crate::storage::{RepoGeneration, Storage};
+use bincode::{Decode, Encode};
+use std::{collections::BTreeSet, io::Write, path::PathBuf};
+use tracing::{info, warn};
+
+#[derive(Encode, Decode)]
+struct CacheFile {
+    generation: u64,
+    hashes: Vec<[u8; 32]>,
+}
+
+// Chunks known to be in the repository, kept on disk between runs so backups don't
+// have to list the whole bucket
+pub struct ChunkCache {
+    // `None` when the storage has no cache dir
+    path: Option<PathBuf>,
+    generation: u64,
+    hashes: BTreeSet<[u8; 32]>,
+    // a list loaded from disk misses chunks uploaded by other machines since
+    complete: bool,
+}
+
+impl ChunkCache {
+    // Uses the local cache if it is from the current generation, lists the bucket otherwise
+    pub async fn load(storage: &Storage) -> anyhow::Result<Self> {
+        let Some(dir) = storage.cache_dir() else {
+            return Self::listed(storage, None, 0).await;
+        };
+        let RepoGeneration {
+            repo_id,
+            generation,
+        } = storage.get_generation().await?;
+        let path = dir.join(hex(&repo_id));
+        let read_path = path.clone();
+        let cached = tokio::task::spawn_blocking(move || std::fs::read(read_path)).await?;
+        let cached = cached.ok().and_then(|bytes| {
+            bincode::decode_from_slice::<CacheFile, _>(&bytes, bincode::config::standard())
+                .map_err(|e| warn!("Ignoring unreadable chunk cache: {e}"))
+                .ok()
+                .map(|x| x.0)
+        });
+        match cached {
+            Some(cached) if cached.generation == generation => {
+                // packed chunks are cheap to list completely, only loose ones get probed
+                storage.refresh_packs().await?;
+                info!("Using {} cached chunk hashes", cached.hashes.len());
+                Ok(Self {
+                    path: Some(path),
+                    generation,
+                    hashes: cached.hashes.into_iter().collect(),
+                    complete: false,
+                })
+            }
+            _ => Self::listed(storage, Some(path), generation).await,
+        }
+    }
+
+    async fn listed(
+        storage: &Storage,
+        path: Option<PathBuf>,
+        generation: u64,
+    ) -> anyhow::Result<Self> {
+        let hashes = storage
+            .available_hashes()
+            .await?
+            .into_iter()
+            .map(<[u8; 32]>::from)
+            .collect();
+        Ok(Self::new(path, generation, hashes))
+    }
+
+    // Cache of the given generation, e.g. for gc which knows the full list anyway
+    pub fn for_generation(
+        storage: &Storage,
+        generation: RepoGeneration,
+        hashes: BTreeSet<[u8; 32]>,
+    ) -> Self {
+        let path = storage
+            .cache_dir()
+            .map(|dir| dir.join(hex(&generation.repo_id)));
+        Self::new(path, generation.generation, hashes)
+    }
+
+    fn new(path: Option<PathBuf>, generation: u64, hashes: BTreeSet<[u8; 32]>) -> Self {
+        Self {
+            path,
+            generation,
+            hashes,
+            complete: true,
+        }
+    }
+
+    // Chunks missing from an incomplete cache still have to be probed with `has_chunk`
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    // Returns false if the hash was already known
+    pub fn insert(&mut self, hash: blake3::Hash) -> bool {
+        self.hashes.insert(hash.into())
+    }
+
+    // Written to a temporary file first, a crash never leaves a truncated cache behind
+    pub async fn save(self) -> anyhow::Result<()> {
+        let Some(path) = self.path else {
+            return Ok(());
+        };
+        let file = CacheFile {
+            generation: self.generation,
+            hashes: self.hashes.into_iter().collect(),
+        };
+        tokio::task::spawn_blocking(move || {
+            let bytes = bincode::encode_to_vec(&file, bincode::config::standard())?;
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            let tmp_path = path.with_extension("tmp");
+            let mut tmp = std::fs::File::create(&tmp_path)?;
+            tmp.write_all(&bytes)?;
+            tmp.sync_all()?;
+            std::fs::rename(&tmp_path, &path)?;
+            anyhow::Ok(())
+        })
+        .await?
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{x:02x}")).collect()
+}
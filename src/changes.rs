@@ -0,0 +1,97 @@
+use crate::blob::Blob;
+use anyhow::Context;
+use std::{ops::Range, path::Path};
+
+// Byte ranges of the source that changed since the previous backup, as reported by
+// changed-block tracking like dm-era or thin_delta
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChangedRegions {
+    // sorted and merged
+    ranges: Vec<Range<u64>>,
+}
+
+impl ChangedRegions {
+    pub fn new(mut ranges: Vec<Range<u64>>) -> Self {
+        ranges.retain(|x| !x.is_empty());
+        ranges.sort_by_key(|x| x.start);
+        let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if last.end >= range.start => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        Self { ranges: merged }
+    }
+
+    // One `<offset> <length>` pair of byte counts per line, `#` starts a comment
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut ranges = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let parse = || {
+                let (offset, len) = line.split_once(char::is_whitespace)?;
+                let offset: u64 = offset.parse().ok()?;
+                let len: u64 = len.trim().parse().ok()?;
+                Some(offset..offset.checked_add(len)?)
+            };
+            let range =
+                parse().with_context(|| format!("invalid changed region on line {}", n + 1))?;
+            ranges.push(range);
+        }
+        Ok(Self::new(ranges))
+    }
+
+    // Bit `i` (least significant bit first) marks block `i` as changed
+    pub fn from_bitmap(bitmap: &[u8], block_size: u64) -> Self {
+        let ranges = bitmap
+            .iter()
+            .enumerate()
+            .flat_map(|(byte_idx, byte)| {
+                (0..8)
+                    .filter(move |bit| byte & (1 << bit) != 0)
+                    .map(move |bit| byte_idx as u64 * 8 + bit)
+            })
+            .map(|block| block * block_size..(block + 1) * block_size)
+            .collect();
+        Self::new(ranges)
+    }
+
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read changed regions {}", path.display()))?;
+        Self::parse(&text)
+    }
+
+    pub fn from_bitmap_file(path: &Path, block_size: u64) -> anyhow::Result<Self> {
+        anyhow::ensure!(block_size > 0, "bitmap block size must not be zero");
+        let bitmap = std::fs::read(path)
+            .with_context(|| format!("failed to read changed bitmap {}", path.display()))?;
+        Ok(Self::from_bitmap(&bitmap, block_size))
+    }
+
+    // For each fixed size chunk of a `size` byte source, the hash in `previous` if the
+    // chunk is untouched and had the same length there
+    pub fn reusable_chunks(
+        &self,
+        previous: &Blob,
+        chunk_size: u64,
+        size: u64,
+    ) -> Vec<Option<blake3::Hash>> {
+        let mut ranges = self.ranges.iter().peekable();
+        let mut previous_chunks = previous.chunks();
+        (0..size.div_ceil(chunk_size))
+            .map(|idx| {
+                let start = idx * chunk_size;
+                let end = size.min(start + chunk_size);
+                while ranges.next_if(|x| x.end <= start).is_some() {}
+                let dirty = ranges.peek().is_some_and(|x| x.start < end);
+                let (hash, len) = previous_chunks.next()?;
+                (!dirty && len == end - start).then_some(hash)
+            })
+            .collect()
+    }
+}
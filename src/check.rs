@@ -0,0 +1,170 @@
+use crate::{blob::ZERO_CHUNK, lock::LockKind, storage::Storage, with_lock};
+use anyhow::Context;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+use tokio::{sync::Semaphore, task::JoinSet};
+use tracing::{error, info};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CheckMode {
+    // only check that referenced chunks exist with the expected size
+    #[default]
+    Fast,
+    // download and re-hash every referenced chunk
+    Full,
+    // download and re-hash a random fraction (0..=1) of the referenced chunks
+    Sample(f64),
+}
+
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub checked_versions: usize,
+    pub checked_chunks: usize,
+    pub downloaded_chunks: usize,
+    pub missing_chunks: Vec<blake3::Hash>,
+    pub corrupt_chunks: Vec<blake3::Hash>,
+    // sets whose history doesn't reconstruct, with the reason
+    pub corrupt_sets: Vec<(String, String)>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing_chunks.is_empty()
+            && self.corrupt_chunks.is_empty()
+            && self.corrupt_sets.is_empty()
+    }
+}
+
+// Verifies every version of every set reconstructs and all chunks they reference exist
+pub async fn check(storage: Storage, mode: CheckMode) -> anyhow::Result<CheckReport> {
+    let lock_storage = storage.clone();
+    with_lock(
+        &lock_storage,
+        LockKind::Shared,
+        "check",
+        check_locked(storage, mode),
+    )
+    .await
+}
+
+async fn check_locked(storage: Storage, mode: CheckMode) -> anyhow::Result<CheckReport> {
+    let (sets, chunks) = tokio::try_join!(storage.list_sets(), storage.list_chunks())?;
+    // listed after the indexes, a pack is always written before its index
+    let pack_sizes = storage
+        .list_packs()
+        .await?
+        .into_iter()
+        .map(|x| (x.id, x.size))
+        .collect::<BTreeMap<_, _>>();
+    let stored = chunks
+        .into_iter()
+        .map(|x| (<[u8; 32]>::from(x.hash), x))
+        .collect::<BTreeMap<_, _>>();
+    let mut report = CheckReport::default();
+
+    // expected length of every referenced chunk, if a version containing it reconstructs
+    let mut referenced = BTreeMap::<[u8; 32], Option<u64>>::new();
+    for set in sets {
+        let doc = storage
+            .with_set(&set)?
+            .get_root_metadata()
+            .await?
+            .with_context(|| format!("root document of set {set} not found"))?;
+        let result = doc.verify_versions(|_, blob| {
+            report.checked_versions += 1;
+            referenced.extend(
+                blob.chunks()
+                    .filter(|(hash, _)| *hash != ZERO_CHUNK)
+                    .map(|(hash, len)| (hash.into(), Some(len))),
+            );
+        });
+        if let Err(e) = result {
+            error!(set, "History is corrupt: {e:#}");
+            report.corrupt_sets.push((set, format!("{e:#}")));
+        }
+        for hash in doc.versions().flat_map(|x| x.unique_chunk_hashes()) {
+            referenced.entry(hash.into()).or_insert(None);
+        }
+    }
+
+    let mut to_download = Vec::new();
+    for (hash, expected_len) in referenced {
+        report.checked_chunks += 1;
+        let hash = blake3::Hash::from_bytes(hash);
+        let Some(chunk) = stored.get(hash.as_bytes()) else {
+            error!("Chunk {hash} is missing");
+            report.missing_chunks.push(hash);
+            continue;
+        };
+        // an index only says where the chunk should be
+        if let Some(pack) = &chunk.pack {
+            let Some(&pack_size) = pack_sizes.get(pack) else {
+                error!("Chunk {hash} is missing, pack {pack} doesn't exist");
+                report.missing_chunks.push(hash);
+                continue;
+            };
+            if pack_size < chunk.offset + chunk.size {
+                error!("Chunk {hash} ends past the end of pack {pack} of size {pack_size}");
+                report.corrupt_chunks.push(hash);
+                continue;
+            }
+        }
+        let stored_size = chunk.size;
+        // compression only ever shrinks chunks
+        let overhead = storage.chunk_overhead();
+        let max_size = expected_len.map(|len| len + overhead);
+        if stored_size < overhead || max_size.is_some_and(|max| stored_size > max) {
+            error!("Chunk {hash} has size {stored_size}, expected at most {max_size:?}");
+            report.corrupt_chunks.push(hash);
+            continue;
+        }
+        let download = match mode {
+            CheckMode::Fast => false,
+            CheckMode::Full => true,
+            CheckMode::Sample(fraction) => rand::random::<f64>() < fraction,
+        };
+        if download {
+            to_download.push(hash);
+        }
+    }
+
+    let mut join_set = JoinSet::new();
+    let semaphore = Arc::new(Semaphore::new(16));
+    for hash in to_download {
+        let permit = semaphore.clone().acquire_owned().await?;
+        let storage = storage.clone();
+        join_set.spawn(async move {
+            let _permit = permit;
+            let matches = async {
+                let data = storage.get_chunk(&hash).await?;
+                anyhow::Ok(storage.chunk_id(&data) == hash)
+            };
+            (hash, matches.await)
+        });
+    }
+    let mut corrupt = BTreeSet::new();
+    while let Some(result) = join_set.join_next().await {
+        let (hash, matches) = result?;
+        report.downloaded_chunks += 1;
+        match matches {
+            Ok(true) => continue,
+            Ok(false) => error!("Chunk {hash} content doesn't match its hash"),
+            // a damaged header or payload fails to decode
+            Err(e) => error!("Chunk {hash} can't be read: {e:#}"),
+        }
+        corrupt.insert(<[u8; 32]>::from(hash));
+    }
+    report
+        .corrupt_chunks
+        .extend(corrupt.into_iter().map(blake3::Hash::from_bytes));
+
+    info!(
+        versions = report.checked_versions,
+        chunks = report.checked_chunks,
+        downloaded = report.downloaded_chunks,
+        "Check finished"
+    );
+    Ok(report)
+}
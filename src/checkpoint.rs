@@ -0,0 +1,105 @@
+use crate::{blob::Blob, chunker::Chunker};
+use anyhow::Context;
+use bincode::{Decode, Encode};
+use rand::Rng;
+use std::{os::unix::fs::FileExt, path::Path, time::UNIX_EPOCH};
+
+// Bytes read between checkpoints of a running backup
+pub const CHECKPOINT_INTERVAL: u64 = 1024 * 1024 * 1024;
+const SAMPLE_COUNT: usize = 16;
+const SAMPLE_SIZE: u64 = 4096;
+
+// Progress of an unfinished backup, lets `backup --resume` skip what was already uploaded
+#[derive(Encode, Decode, Clone, Debug)]
+pub struct Checkpoint {
+    pub chunker: Chunker,
+    pub source: SourceInfo,
+    // chunks of a prefix of the source, all of them are in the repository
+    pub blob: Blob,
+}
+
+impl Checkpoint {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.chunker.validate()?;
+        self.blob.check_invariants()?;
+        self.blob.check_chunking(&self.chunker)
+    }
+}
+
+// Identifies the source a checkpoint was taken from
+#[derive(Encode, Decode, Clone, Debug)]
+pub struct SourceInfo {
+    size: u64,
+    mtime_nanos: i64,
+    // hashes of a few random blocks of the checkpointed prefix, as mtime means
+    // little for block devices
+    samples: Vec<Sample>,
+}
+
+#[derive(Encode, Decode, Clone, Debug)]
+struct Sample {
+    offset: u64,
+    len: u32,
+    hash: [u8; 32],
+}
+
+impl SourceInfo {
+    // Size and mtime only, `sample` adds the samples
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::File::open(path)?;
+        let size = crate::sparse::device_size(&file)?;
+        let mtime = file.metadata()?.modified()?.duration_since(UNIX_EPOCH)?;
+        Ok(Self {
+            size,
+            mtime_nanos: mtime.as_nanos().try_into()?,
+            samples: Vec::new(),
+        })
+    }
+
+    // Samples blocks of the first `covered` bytes, hashed with `hash` so encrypted
+    // repositories don't store plain hashes of the source
+    pub fn sample(
+        &mut self,
+        path: &Path,
+        covered: u64,
+        hash: impl Fn(&[u8]) -> blake3::Hash,
+    ) -> anyhow::Result<()> {
+        let file = std::fs::File::open(path)?;
+        let mut rng = rand::thread_rng();
+        self.samples.clear();
+        if covered == 0 {
+            return Ok(());
+        }
+        for _ in 0..SAMPLE_COUNT {
+            let offset = rng.gen_range(0..covered);
+            let mut block = vec![0; SAMPLE_SIZE.min(covered - offset) as usize];
+            file.read_exact_at(&mut block, offset)?;
+            self.samples.push(Sample {
+                offset,
+                len: block.len() as u32,
+                hash: hash(&block).into(),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn verify(&self, path: &Path, hash: impl Fn(&[u8]) -> blake3::Hash) -> anyhow::Result<()> {
+        let current = Self::read(path)?;
+        anyhow::ensure!(
+            current.size == self.size && current.mtime_nanos == self.mtime_nanos,
+            "source size or modification time changed since the checkpoint"
+        );
+        let file = std::fs::File::open(path)?;
+        for sample in &self.samples {
+            let mut block = vec![0; sample.len as usize];
+            file.read_exact_at(&mut block, sample.offset)
+                .context("failed to read checkpoint sample")?;
+            anyhow::ensure!(
+                hash(&block) == sample.hash,
+                "source content at offset {} changed since the checkpoint",
+                sample.offset
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,252 @@
+use crate::read_full;
+use bincode::{Decode, Encode};
+use humansize::{format_size, BINARY};
+use std::{fmt, io::Read, str::FromStr};
+
+// 512kb, used unless the repository or set picks another size
+pub const DEFAULT_CHUNK_SIZE: u32 = 512 * 1024;
+
+// How a source is split into chunks, recorded in the `Document` of each set
+#[derive(Encode, Decode, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Chunker {
+    // split at fixed offsets, ideal for block images
+    Fixed {
+        chunk_size: u32,
+    },
+    // FastCDC content-defined boundaries, keeps deduplicating when data shifts
+    Cdc {
+        min_size: u32,
+        avg_size: u32,
+        max_size: u32,
+    },
+}
+
+impl Chunker {
+    pub const DEFAULT: Self = Self::Fixed {
+        chunk_size: DEFAULT_CHUNK_SIZE,
+    };
+
+    pub fn cdc(avg_size: u32) -> Self {
+        Self::Cdc {
+            min_size: avg_size / 4,
+            avg_size,
+            max_size: avg_size * 4,
+        }
+    }
+
+    // Same kind of chunking, with `chunk_size` as the fixed or average size
+    pub fn with_chunk_size(self, chunk_size: u32) -> Self {
+        match self {
+            Self::Fixed { .. } => Self::Fixed { chunk_size },
+            Self::Cdc { .. } => Self::cdc(chunk_size),
+        }
+    }
+
+    // Fixed size, or the average size for cdc
+    pub fn chunk_size(&self) -> u32 {
+        match *self {
+            Self::Fixed { chunk_size } => chunk_size,
+            Self::Cdc { avg_size, .. } => avg_size,
+        }
+    }
+
+    // Checks that `lengths` could have been produced by this chunker
+    pub fn check_lengths(&self, lengths: impl ExactSizeIterator<Item = u64>) -> anyhow::Result<()> {
+        let count = lengths.len();
+        let (min, max) = match *self {
+            Self::Fixed { chunk_size } => (chunk_size, chunk_size),
+            Self::Cdc {
+                min_size, max_size, ..
+            } => (min_size, max_size),
+        };
+        for (idx, len) in lengths.enumerate() {
+            let last = idx + 1 == count;
+            // only the last chunk may be short
+            anyhow::ensure!(
+                len <= max.into() && (last || len >= min.into()),
+                "chunk {idx} has length {len}, which {self} chunking can't produce"
+            );
+        }
+        Ok(())
+    }
+
+    pub fn max_chunk_size(&self) -> usize {
+        match *self {
+            Self::Fixed { chunk_size } => chunk_size as usize,
+            Self::Cdc { max_size, .. } => max_size as usize,
+        }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        const MAX: u32 = 64 * 1024 * 1024;
+        match *self {
+            Self::Fixed { chunk_size } => {
+                anyhow::ensure!(
+                    (1..=MAX).contains(&chunk_size),
+                    "chunk size must be between 1 byte and 64 MiB"
+                );
+            }
+            Self::Cdc {
+                min_size,
+                avg_size,
+                max_size,
+            } => {
+                anyhow::ensure!(
+                    64 <= min_size && min_size <= avg_size && avg_size <= max_size,
+                    "cdc sizes must satisfy 64 <= min <= avg <= max"
+                );
+                anyhow::ensure!(max_size <= MAX, "cdc max size must be at most 64 MiB");
+                anyhow::ensure!(
+                    avg_size.is_power_of_two(),
+                    "cdc average size must be a power of two"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn reader<R: Read>(self, reader: R) -> ChunkReader<R> {
+        ChunkReader {
+            reader,
+            chunker: self,
+            buf: vec![0; self.max_chunk_size()],
+            filled: 0,
+            eof: false,
+        }
+    }
+
+    // Length of the next chunk at the start of `data`, which holds at least
+    // `max_chunk_size` bytes unless the source ends earlier
+    fn cut(&self, data: &[u8]) -> usize {
+        match *self {
+            Self::Fixed { chunk_size } => data.len().min(chunk_size as usize),
+            Self::Cdc {
+                min_size,
+                avg_size,
+                max_size,
+            } => cdc_cut(
+                data,
+                min_size as usize,
+                avg_size as usize,
+                max_size as usize,
+            ),
+        }
+    }
+}
+
+impl Default for Chunker {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl FromStr for Chunker {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "fixed" => Ok(Self::DEFAULT),
+            "cdc" => Ok(Self::cdc(DEFAULT_CHUNK_SIZE)),
+            _ => anyhow::bail!("invalid chunker {s:?}, expected fixed or cdc"),
+        }
+    }
+}
+
+impl fmt::Display for Chunker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Fixed { chunk_size } => {
+                write!(f, "fixed {}", format_size(chunk_size, BINARY))
+            }
+            Self::Cdc {
+                min_size,
+                avg_size,
+                max_size,
+            } => write!(
+                f,
+                "cdc {}..{}, average {}",
+                format_size(min_size, BINARY),
+                format_size(max_size, BINARY),
+                format_size(avg_size, BINARY)
+            ),
+        }
+    }
+}
+
+// Splits a byte stream into chunks according to a `Chunker`
+pub struct ChunkReader<R> {
+    reader: R,
+    chunker: Chunker,
+    buf: Vec<u8>,
+    filled: usize,
+    eof: bool,
+}
+
+impl<R: Read> ChunkReader<R> {
+    pub fn next_chunk(&mut self) -> std::io::Result<Option<Vec<u8>>> {
+        if !self.eof && self.filled < self.buf.len() {
+            let wanted = self.buf.len() - self.filled;
+            let len = read_full(&mut self.reader, &mut self.buf[self.filled..])?;
+            self.eof = len < wanted;
+            self.filled += len;
+        }
+        if self.filled == 0 {
+            return Ok(None);
+        }
+        let cut = self.chunker.cut(&self.buf[..self.filled]);
+        let chunk = self.buf[..cut].to_vec();
+        self.buf.copy_within(cut..self.filled, 0);
+        self.filled -= cut;
+        Ok(Some(chunk))
+    }
+}
+
+// Random but fixed forever, changing it moves every cdc chunk boundary
+const GEAR: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut state: u64 = 0x6275_7020_6364_6300;
+    let mut i = 0;
+    while i < table.len() {
+        // splitmix64
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+// The gear hash shifts left, so the top bits depend on the most bytes
+const fn top_bits_mask(bits: u32) -> u64 {
+    !(u64::MAX >> bits)
+}
+
+// FastCDC with normalized chunking: a stricter mask before the average size and a
+// looser one after it pulls chunk sizes towards the average
+fn cdc_cut(data: &[u8], min_size: usize, avg_size: usize, max_size: usize) -> usize {
+    let end = data.len().min(max_size);
+    if end <= min_size {
+        return end;
+    }
+    let bits = avg_size.ilog2();
+    let strict_mask = top_bits_mask(bits + 2);
+    let loose_mask = top_bits_mask(bits - 2);
+    let normal = avg_size.min(end);
+
+    let mut hash = 0u64;
+    for (i, &byte) in data.iter().enumerate().take(normal).skip(min_size) {
+        hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+        if hash & strict_mask == 0 {
+            return i + 1;
+        }
+    }
+    for (i, &byte) in data.iter().enumerate().take(end).skip(normal) {
+        hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+        if hash & loose_mask == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
@@ -0,0 +1,99 @@
+use std::{fmt, str::FromStr};
+
+// Every stored chunk starts with this header:
+// format version (1 byte), codec (1 byte), uncompressed length (u32 little endian)
+pub const HEADER_LEN: usize = 6;
+const FORMAT_VERSION: u8 = 1;
+const CODEC_RAW: u8 = 0;
+const CODEC_ZSTD: u8 = 1;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd {
+        level: i32,
+    },
+}
+
+impl Compression {
+    pub const ZSTD_DEFAULT_LEVEL: i32 = 3;
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.split_once(':') {
+            None if s == "none" => Ok(Self::None),
+            None if s == "zstd" => Ok(Self::Zstd {
+                level: Self::ZSTD_DEFAULT_LEVEL,
+            }),
+            Some(("zstd", level)) => {
+                let level = level.parse()?;
+                anyhow::ensure!(
+                    zstd::compression_level_range().contains(&level),
+                    "zstd level {level} is out of range"
+                );
+                Ok(Self::Zstd { level })
+            }
+            _ => anyhow::bail!("invalid compression {s:?}, expected none, zstd or zstd:<level>"),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Zstd { level } => write!(f, "zstd:{level}"),
+        }
+    }
+}
+
+// Compressed data is only kept if it saves at least 1/16 of the size,
+// otherwise decompressing isn't worth it (e.g. LUKS data is incompressible)
+pub fn encode_chunk(data: &[u8], compression: Compression) -> anyhow::Result<Vec<u8>> {
+    let len = u32::try_from(data.len())?;
+    if let Compression::Zstd { level } = compression {
+        let compressed = zstd::bulk::compress(data, level)?;
+        if compressed.len() < data.len() - data.len() / 16 {
+            return Ok(with_header(CODEC_ZSTD, len, &compressed));
+        }
+    }
+    Ok(with_header(CODEC_RAW, len, data))
+}
+
+fn with_header(codec: u8, len: u32, payload: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(HEADER_LEN + payload.len());
+    encoded.push(FORMAT_VERSION);
+    encoded.push(codec);
+    encoded.extend_from_slice(&len.to_le_bytes());
+    encoded.extend_from_slice(payload);
+    encoded
+}
+
+pub fn decode_chunk(encoded: &[u8]) -> anyhow::Result<Vec<u8>> {
+    anyhow::ensure!(
+        encoded.len() >= HEADER_LEN,
+        "chunk is shorter than its header"
+    );
+    let (header, payload) = encoded.split_at(HEADER_LEN);
+    anyhow::ensure!(
+        header[0] == FORMAT_VERSION,
+        "unknown chunk format version {}",
+        header[0]
+    );
+    let len = u32::from_le_bytes(header[2..].try_into().unwrap()) as usize;
+    let data = match header[1] {
+        CODEC_RAW => payload.to_vec(),
+        CODEC_ZSTD => zstd::bulk::decompress(payload, len)?,
+        codec => anyhow::bail!("unknown chunk codec {codec}"),
+    };
+    anyhow::ensure!(
+        data.len() == len,
+        "chunk has length {}, header says {len}",
+        data.len()
+    );
+    Ok(data)
+}
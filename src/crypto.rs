@@ -0,0 +1,113 @@
+use anyhow::Context;
+use argon2::Argon2;
+use bincode::{Decode, Encode};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use rand::{thread_rng, RngCore};
+
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+// Added to every encrypted object: a random nonce in front, the poly1305 tag at the end
+pub const OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+
+const CHUNK_ID_CONTEXT: &str = "bup 2024-11-01 chunk id";
+const ENCRYPTION_CONTEXT: &str = "bup 2024-11-01 object encryption";
+const KEY_CHECK_CONTEXT: &str = "bup 2024-11-01 key check";
+
+// Stored in clear in the repository config, so it must not contain anything secret
+#[derive(Encode, Decode, Clone, Debug, PartialEq)]
+pub struct EncryptionConfig {
+    salt: [u8; 16],
+    // tells a wrong key apart from corrupted objects
+    key_check: [u8; 32],
+}
+
+impl EncryptionConfig {
+    // New config with a random salt, along with the key derived from `secret`
+    pub fn generate(secret: &[u8]) -> anyhow::Result<(Self, RepoKey)> {
+        let mut salt = [0; 16];
+        thread_rng().fill_bytes(&mut salt);
+        let master = derive_master_key(secret, &salt)?;
+        let config = Self {
+            salt,
+            key_check: blake3::derive_key(KEY_CHECK_CONTEXT, &master),
+        };
+        Ok((config, RepoKey::from_master_key(&master)))
+    }
+
+    pub fn unlock(&self, secret: &[u8]) -> anyhow::Result<RepoKey> {
+        let master = derive_master_key(secret, &self.salt)?;
+        anyhow::ensure!(
+            blake3::derive_key(KEY_CHECK_CONTEXT, &master) == self.key_check,
+            "wrong repository key"
+        );
+        Ok(RepoKey::from_master_key(&master))
+    }
+}
+
+// The secret is a passphrase or keyfile contents, argon2 makes guessing passphrases expensive
+fn derive_master_key(secret: &[u8], salt: &[u8]) -> anyhow::Result<[u8; 32]> {
+    let mut master = [0; 32];
+    Argon2::default()
+        .hash_password_into(secret, salt, &mut master)
+        .map_err(|e| anyhow::anyhow!("failed to derive repository key: {e}"))?;
+    Ok(master)
+}
+
+#[derive(Clone)]
+pub struct RepoKey {
+    // chunk names are keyed hashes, so the bucket can't be probed for known content
+    id_key: [u8; 32],
+    cipher: XChaCha20Poly1305,
+}
+
+impl RepoKey {
+    fn from_master_key(master: &[u8; 32]) -> Self {
+        let encryption_key = blake3::derive_key(ENCRYPTION_CONTEXT, master);
+        Self {
+            id_key: blake3::derive_key(CHUNK_ID_CONTEXT, master),
+            cipher: XChaCha20Poly1305::new(&encryption_key.into()),
+        }
+    }
+
+    pub fn chunk_id(&self, data: &[u8]) -> blake3::Hash {
+        blake3::keyed_hash(&self.id_key, data)
+    }
+
+    // `aad` binds the ciphertext to the key it is stored under, so objects can't be swapped
+    pub fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let mut nonce = XNonce::default();
+        thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .expect("encryption only fails for oversized inputs");
+        let mut sealed = Vec::with_capacity(OVERHEAD + plaintext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    pub fn open(&self, sealed: &[u8], aad: &[u8]) -> anyhow::Result<Vec<u8>> {
+        anyhow::ensure!(sealed.len() >= OVERHEAD, "encrypted object is truncated");
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .ok()
+            .context("encrypted object failed authentication")
+    }
+}
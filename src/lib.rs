@@ -1,174 +1,928 @@
 #![allow(dead_code)]
 pub mod blob;
+pub mod cache;
+pub mod changes;
+pub mod check;
+pub mod checkpoint;
+pub mod chunker;
+pub mod codec;
+pub mod crypto;
+pub mod lock;
+pub mod nbd;
+pub mod output;
+pub mod pack;
+pub mod reader;
+pub mod retention;
+pub mod sparse;
 pub mod storage;
 
 #[cfg(test)]
 mod tests;
 
 use anyhow::Context;
+use cache::ChunkCache;
+use changes::ChangedRegions;
+use checkpoint::{Checkpoint, SourceInfo, CHECKPOINT_INTERVAL};
+use chunker::Chunker;
 use futures::executor::block_on;
-use std::collections::BTreeSet;
-use std::io::{ErrorKind, Read, Write};
+use lock::{LockKind, RepoLock};
+use output::RestoreOutput;
+use pack::{PackBuilder, DEFAULT_PACK_SIZE};
+use retention::RetentionPolicy;
+use sparse::SparseFile;
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
 use std::path::Path;
-use std::sync::Arc;
-use storage::Storage;
+use std::sync::{Arc, Mutex};
+use storage::{ChunkMeta, GcMark, RootConflict, Storage};
 use tokio::sync::{mpsc, Semaphore};
 use tokio::task::JoinSet;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
-// 512kb
-pub const CHUNK_SIZE: usize = 512 * 1024;
-
-use blob::{Blob, Document};
+use blob::{is_zero_chunk, Blob, Document, VersionSpec, ZERO_CHUNK};
 
 const HASH_CHANNEL_SIZE: usize = 400;
+const ROOT_UPDATE_ATTEMPTS: usize = 10;
+pub const RESTORE_CONCURRENCY: usize = 16;
+
+// Read-modify-write of the root document. `f` gets the latest document and returns the
+// new one (or `None` to leave it alone), it is called again if another writer won the race.
+async fn update_root(
+    storage: &Storage,
+    mut f: impl FnMut(Option<Document>) -> anyhow::Result<Option<Document>>,
+) -> anyhow::Result<()> {
+    for attempt in 1..=ROOT_UPDATE_ATTEMPTS {
+        let (doc, version) = storage.get_root_metadata_for_update().await?;
+        let Some(doc) = f(doc)? else {
+            return Ok(());
+        };
+        match storage.put_root_metadata(doc, &version).await {
+            Err(e) if e.is::<RootConflict>() => {
+                warn!(attempt, "Root document changed concurrently, retrying");
+            }
+            result => return result,
+        }
+    }
+    anyhow::bail!(
+        "root document of set {} kept changing concurrently, gave up after {ROOT_UPDATE_ATTEMPTS} attempts",
+        storage.set_name()
+    )
+}
+
+// Like `read_exact`, but returns the number of bytes read instead of failing at end of file.
+pub(crate) fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+// Runs `operation` while holding a repository lock, the lock is released even on error.
+// The operation is aborted as soon as the lock is lost.
+async fn with_lock<T>(
+    storage: &Storage,
+    kind: LockKind,
+    name: &str,
+    operation: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    let lock = RepoLock::acquire(storage, kind, name).await?;
+    let result = tokio::select! {
+        result = operation => result,
+        () = lock.lost() => Err(anyhow::anyhow!("repository lock was lost, aborted {name}")),
+    };
+    let released = lock.release().await;
+    let value = result?;
+    released?;
+    Ok(value)
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct BackupOptions {
+    // chunker of a new set, existing sets keep the one they were created with.
+    // Defaults to the one in the repository config.
+    pub chunker: Option<Chunker>,
+    // overrides the fixed or average chunk size of a new set
+    pub chunk_size: Option<u32>,
+    // only these are read, other chunks are taken from the previous version.
+    // Needs fixed chunking.
+    pub changed_regions: Option<ChangedRegions>,
+    // read everything when the version count is a multiple of this, in case the
+    // changed regions missed something
+    pub full_pass_every: Option<usize>,
+    // continue from the checkpoint of an interrupted backup of the same source
+    pub resume: bool,
+    // bytes between checkpoints, defaults to `CHECKPOINT_INTERVAL`
+    pub checkpoint_interval: Option<u64>,
+}
+
 pub async fn backup(storage: Storage, file: &Path) -> anyhow::Result<()> {
+    backup_with_options(storage, file, &BackupOptions::default()).await
+}
+
+pub async fn backup_with_options(
+    storage: Storage,
+    file: &Path,
+    options: &BackupOptions,
+) -> anyhow::Result<()> {
+    let lock_storage = storage.clone();
+    let operation = backup_locked(storage, file, options);
+    with_lock(&lock_storage, LockKind::Shared, "backup", operation).await
+}
+
+async fn backup_locked(
+    storage: Storage,
+    file: &Path,
+    options: &BackupOptions,
+) -> anyhow::Result<()> {
+    let (doc, config) = tokio::try_join!(storage.get_root_metadata(), storage.get_config())?;
+    // chunks written without the key would be stored in clear
+    anyhow::ensure!(
+        storage.is_encrypted() || config.as_ref().is_none_or(|x| x.encryption.is_none()),
+        "repository is encrypted, a key is required"
+    );
+    let previous = doc
+        .as_ref()
+        .map(|doc| (doc.current().clone(), doc.version_count()));
+    let chunker = match doc {
+        Some(doc) => {
+            let existing = doc.chunker();
+            let requested = options.chunker.unwrap_or(existing);
+            let requested = options
+                .chunk_size
+                .map_or(requested, |size| requested.with_chunk_size(size));
+            anyhow::ensure!(
+                existing == requested,
+                "set {} uses {existing} chunking, cannot switch to {requested}",
+                storage.set_name()
+            );
+            existing
+        }
+        None => {
+            let chunker = options
+                .chunker
+                .or(config.map(|x| x.chunker))
+                .unwrap_or_default();
+            options
+                .chunk_size
+                .map_or(chunker, |size| chunker.with_chunk_size(size))
+        }
+    };
+    chunker.validate()?;
+    let reuse_plan = match &options.changed_regions {
+        Some(regions) => plan_reuse(file, regions, previous.as_ref(), chunker, options)?,
+        None => None,
+    };
+
+    let source = SourceInfo::read(file)?;
+    let resumed = match options.resume {
+        true => resume_from(&storage, file, chunker).await?,
+        false => None,
+    };
+    let start_idx = resumed.as_ref().map_or(0, |x| x.chunk_count());
+    let start_offset = resumed.as_ref().map_or(0, |x| x.size());
+    let checkpoint_interval = options.checkpoint_interval.unwrap_or(CHECKPOINT_INTERVAL);
+
+    // on a full pass, chunks the changed regions claim to be unchanged are compared
+    let full_pass_expected = match &reuse_plan {
+        Some(plan) if plan.full_pass => Some(plan.reusable.clone()),
+        _ => None,
+    };
+
     #[derive(Debug, Clone)]
     struct Chunk {
         idx: usize,
-        data: Vec<u8>,
+        len: usize,
+        // `None` for chunks taken from the previous version or lying in a hole, which
+        // aren't read
+        data: Option<Vec<u8>>,
     }
     let (hash_tx, mut hash_rx) = mpsc::channel::<(blake3::Hash, Chunk)>(HASH_CHANNEL_SIZE);
     let file_path = file.to_owned();
-    let chunk_reader = tokio::spawn(async move {
+    let chunk_id = storage.chunk_hasher();
+    // not spawned, so dropping the backup also stops reading and uploading
+    let chunk_reader = async move {
         tokio::task::spawn_blocking(move || {
-            let mut file = std::fs::File::open(file_path)?;
-
-            for idx in 0.. {
-                let hash_permit = block_on(hash_tx.clone().reserve_owned()).unwrap();
-                let mut buffer = vec![0; CHUNK_SIZE];
-                match file.read_exact(&mut buffer) {
-                    Ok(()) => {
-                        let chunk = Chunk { idx, data: buffer };
-                        rayon::spawn_fifo(move || {
-                            let hash = blake3::hash(&chunk.data);
-                            hash_permit.send((hash, chunk));
-                        });
+            let mut file = SparseFile::open(&file_path)?;
+            let source_size = file.size();
+            let hash_chunk = |hash_permit: mpsc::OwnedPermit<_>, chunk: Chunk| {
+                let chunk_id = chunk_id.clone();
+                rayon::spawn_fifo(move || {
+                    let data = chunk.data.as_deref().unwrap();
+                    let hash = match is_zero_chunk(data) {
+                        true => ZERO_CHUNK,
+                        false => chunk_id(data),
+                    };
+                    hash_permit.send((hash, chunk));
+                });
+            };
+            // chunks at known offsets, those entirely in a hole are zero without reading
+            let mut read_at = |hash_permit: mpsc::OwnedPermit<_>, idx, offset, len| {
+                let data = file.read_chunk_at(offset, len)?;
+                let chunk = Chunk { idx, len, data };
+                match chunk.data {
+                    Some(_) => hash_chunk(hash_permit, chunk),
+                    None => {
+                        hash_permit.send((ZERO_CHUNK, chunk));
                     }
-                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
-                    Err(e) => return Err(e.into()),
                 }
+                anyhow::Ok(())
+            };
+            if let Some(ReusePlan {
+                chunk_size,
+                size,
+                reusable,
+                full_pass: false,
+            }) = reuse_plan
+            {
+                for (idx, reused) in reusable.into_iter().enumerate().skip(start_idx) {
+                    let hash_permit = block_on(hash_tx.clone().reserve_owned())?;
+                    let offset = idx as u64 * chunk_size;
+                    let len = (size - offset).min(chunk_size) as usize;
+                    if let Some(hash) = reused {
+                        hash_permit.send((
+                            hash,
+                            Chunk {
+                                idx,
+                                len,
+                                data: None,
+                            },
+                        ));
+                        continue;
+                    }
+                    read_at(hash_permit, idx, offset, len)?;
+                }
+                return anyhow::Ok(());
+            }
+            if let Chunker::Fixed { chunk_size } = chunker {
+                let mut offset = start_offset;
+                for idx in start_idx.. {
+                    if offset >= source_size {
+                        break;
+                    }
+                    let hash_permit = block_on(hash_tx.clone().reserve_owned())?;
+                    let len = (source_size - offset).min(chunk_size.into());
+                    read_at(hash_permit, idx, offset, len as usize)?;
+                    offset += len;
+                }
+                return anyhow::Ok(());
+            }
+
+            // chunk boundaries only depend on the data after the previous boundary, holes
+            // are zero-filled instead of read
+            file.seek(SeekFrom::Start(start_offset))?;
+            let mut chunks = chunker.reader(file);
+            for idx in start_idx.. {
+                let hash_permit = block_on(hash_tx.clone().reserve_owned())?;
+                let Some(data) = chunks.next_chunk()? else {
+                    break;
+                };
+                let len = data.len();
+                hash_chunk(
+                    hash_permit,
+                    Chunk {
+                        idx,
+                        len,
+                        data: Some(data),
+                    },
+                );
             }
             anyhow::Ok(())
         })
         .await??;
 
         Ok::<(), anyhow::Error>(())
-    });
+    };
 
-    let upload_task = tokio::spawn(async move {
-        let (doc, available_hashes) =
-            tokio::try_join!(storage.get_root_metadata(), storage.available_hashes())?;
+    let source_path = file.to_owned();
+    let upload_task = async move {
+        let mut hashes_sent = ChunkCache::load(&storage).await?;
+        let probe = !hashes_sent.is_complete();
         let mut new_blob = Blob::empty();
-        let mut hashes_sent = available_hashes
-            .into_iter()
-            .map(<[u8; 32]>::from)
-            .collect::<BTreeSet<_>>();
+        for (idx, (hash, len)) in resumed.iter().flat_map(|x| x.chunks()).enumerate() {
+            new_blob.set(idx, hash, len as usize);
+        }
 
         let mut join_set = JoinSet::new();
         let semaphore = Arc::new(Semaphore::new(16));
+        let packs = storage
+            .pack_size()
+            .map(|size| Arc::new(Mutex::new(PackBuilder::new(size))));
 
+        let mut size = start_offset;
+        let mut missed_changes = 0;
+        // chunks arrive out of order, a checkpoint covers those before the first gap
+        let mut received = BTreeSet::new();
+        let mut prefix_len = start_idx;
+        let mut since_checkpoint = 0;
         while let Some((hash, chunk)) = hash_rx.recv().await {
-            new_blob.set(chunk.idx, hash);
-            if hashes_sent.insert(hash.into()) {
+            // before handling the next chunk, so all covered chunks were handed to uploads
+            if since_checkpoint >= checkpoint_interval {
+                since_checkpoint = 0;
+                finish_uploads(&storage, &mut join_set, packs.as_deref()).await?;
+                let blob = new_blob.prefix(prefix_len);
+                let mut source = source.clone();
+                let (path, covered) = (source_path.clone(), blob.size());
+                let chunk_id = storage.chunk_hasher();
+                let source = tokio::task::spawn_blocking(move || {
+                    source.sample(&path, covered, chunk_id)?;
+                    anyhow::Ok(source)
+                })
+                .await??;
+                info!("Checkpoint at {covered} bytes");
+                let checkpoint = Checkpoint {
+                    chunker,
+                    source,
+                    blob,
+                };
+                storage.put_checkpoint(&checkpoint).await?;
+            }
+            new_blob.set(chunk.idx, hash, chunk.len);
+            size += chunk.len as u64;
+            received.insert(chunk.idx);
+            while received.remove(&prefix_len) {
+                prefix_len += 1;
+            }
+            since_checkpoint += chunk.len as u64;
+            let expected = full_pass_expected
+                .as_ref()
+                .and_then(|x| x.get(chunk.idx).copied().flatten());
+            if expected.is_some_and(|x| x != hash) {
+                missed_changes += 1;
+            }
+            if hash == ZERO_CHUNK {
+                continue;
+            }
+            let Some(data) = chunk.data else {
+                // still referenced by the previous version, so it is in the repository
+                hashes_sent.insert(hash);
+                continue;
+            };
+            if hashes_sent.insert(hash) {
                 let permit = semaphore.clone().acquire_owned().await?;
                 let storage = storage.clone();
+                let packs = packs.clone();
                 join_set.spawn(async move {
                     let _permit = permit;
-                    info!(idx = chunk.idx, "Uploading chunk");
-                    storage.put_chunk(&hash, chunk.data).await
+                    // may have been uploaded by another machine since the cache was written
+                    if probe && storage.probe_chunk(&hash).await? {
+                        return Ok(());
+                    }
+                    let Some(packs) = packs else {
+                        info!(idx = chunk.idx, "Uploading chunk");
+                        return storage.put_chunk(&hash, data).await;
+                    };
+                    let encoded = storage.encode_chunk(&hash, data).await?;
+                    let full_pack = packs.lock().unwrap().add(hash, &encoded);
+                    if let Some(pack) = full_pack {
+                        info!(idx = chunk.idx, "Uploading pack");
+                        storage.put_pack(pack).await?;
+                    }
+                    Ok(())
                 });
             }
         }
 
-        while let Some(result) = join_set.join_next().await {
-            result??;
+        if missed_changes > 0 {
+            warn!("Full pass found {missed_changes} changed chunks outside the changed regions");
         }
+        // the root may only reference chunks that are uploaded
+        finish_uploads(&storage, &mut join_set, packs.as_deref()).await?;
+        new_blob.set_size(size);
 
-        let doc = match doc {
-            Some(mut doc) => {
-                doc.update(new_blob);
-                doc
-            }
-            None => Document::new(new_blob),
-        };
-
-        storage.put_root_metadata(doc).await?;
-        anyhow::Ok(())
-    });
+        update_root(&storage, |doc| {
+            let doc = match doc {
+                Some(mut doc) => {
+                    anyhow::ensure!(
+                        doc.chunker() == chunker,
+                        "chunker of set {} changed during backup",
+                        storage.set_name()
+                    );
+                    doc.update(new_blob.clone());
+                    doc
+                }
+                None => Document::new(new_blob.clone(), chunker),
+            };
+            Ok(Some(doc))
+        })
+        .await?;
+        storage.delete_checkpoint().await?;
+        hashes_sent.save().await
+    };
 
-    // Wait for all tasks to complete
-    let (chunk_result, upload_result) = tokio::try_join!(chunk_reader, upload_task)?;
-    chunk_result?;
-    upload_result?;
+    tokio::try_join!(chunk_reader, upload_task)?;
+    Ok(())
+}
 
+// Waits for running uploads and uploads the partial pack, afterwards every chunk
+// handed to an upload task is in the repository
+async fn finish_uploads(
+    storage: &Storage,
+    join_set: &mut JoinSet<anyhow::Result<()>>,
+    packs: Option<&Mutex<PackBuilder>>,
+) -> anyhow::Result<()> {
+    while let Some(result) = join_set.join_next().await {
+        result??;
+    }
+    let last_pack = packs.and_then(|x| x.lock().unwrap().finish());
+    if let Some(pack) = last_pack {
+        storage.put_pack(pack).await?;
+    }
     Ok(())
 }
 
-pub async fn restore(storage: Storage, output_path: &Path) -> anyhow::Result<()> {
+// Chunks of the checkpointed prefix, if there is a checkpoint for this source
+async fn resume_from(
+    storage: &Storage,
+    file: &Path,
+    chunker: Chunker,
+) -> anyhow::Result<Option<Blob>> {
+    let Some(checkpoint) = storage.get_checkpoint().await? else {
+        info!("No checkpoint to resume from, starting over");
+        return Ok(None);
+    };
+    anyhow::ensure!(
+        checkpoint.chunker == chunker,
+        "checkpoint uses {} chunking, set uses {chunker}",
+        checkpoint.chunker
+    );
+    let path = file.to_owned();
+    let chunk_id = storage.chunk_hasher();
+    let source = checkpoint.source;
+    tokio::task::spawn_blocking(move || source.verify(&path, chunk_id))
+        .await?
+        .context("cannot resume, run the backup without --resume to start over")?;
+    info!("Resuming at {} bytes", checkpoint.blob.size());
+    Ok(Some(checkpoint.blob))
+}
+
+struct ReusePlan {
+    chunk_size: u64,
+    size: u64,
+    reusable: Vec<Option<blake3::Hash>>,
+    full_pass: bool,
+}
+
+fn plan_reuse(
+    file: &Path,
+    regions: &ChangedRegions,
+    previous: Option<&(Blob, usize)>,
+    chunker: Chunker,
+    options: &BackupOptions,
+) -> anyhow::Result<Option<ReusePlan>> {
+    let Chunker::Fixed { chunk_size } = chunker else {
+        anyhow::bail!("changed regions need fixed chunking, not {chunker}");
+    };
+    let Some((previous, version_count)) = previous else {
+        info!("No previous version, reading everything");
+        return Ok(None);
+    };
+    let full_pass = options
+        .full_pass_every
+        .is_some_and(|n| version_count % n.max(1) == 0);
+    if full_pass {
+        info!("Full pass, reading everything");
+    }
+    let size = sparse::device_size(&std::fs::File::open(file)?)?;
+    let reusable = regions.reusable_chunks(previous, chunk_size.into(), size);
+    Ok(Some(ReusePlan {
+        chunk_size: chunk_size.into(),
+        size,
+        reusable,
+        full_pass,
+    }))
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RestoreOptions {
+    // keep the existing output and only write chunks that differ from the version
+    pub in_place: bool,
+    // chunks fetched at the same time, defaults to `RESTORE_CONCURRENCY`
+    pub concurrency: Option<usize>,
+    // overwrite an existing non-empty output
+    pub force: bool,
+    // write aligned chunks with O_DIRECT, bypassing the page cache
+    pub direct: bool,
+    // punch holes for zero chunks over existing data instead of writing zeros, zero
+    // chunks past the end of a regular file are always left as holes
+    pub sparse: bool,
+}
+
+pub async fn restore(
+    storage: Storage,
+    output_path: &Path,
+    version: VersionSpec,
+) -> anyhow::Result<()> {
+    restore_with_options(storage, output_path, version, &RestoreOptions::default()).await
+}
+
+pub async fn restore_with_options(
+    storage: Storage,
+    output_path: &Path,
+    version: VersionSpec,
+    options: &RestoreOptions,
+) -> anyhow::Result<()> {
+    let lock_storage = storage.clone();
+    let operation = restore_locked(storage, output_path, version, options);
+    with_lock(&lock_storage, LockKind::Shared, "restore", operation).await
+}
+
+async fn restore_locked(
+    storage: Storage,
+    output_path: &Path,
+    version: VersionSpec,
+    options: &RestoreOptions,
+) -> anyhow::Result<()> {
     const CHANNEL_SIZE: usize = 400;
-    let (chunk_tx, mut chunk_rx) = mpsc::channel(CHANNEL_SIZE);
+    let concurrency = options.concurrency.unwrap_or(RESTORE_CONCURRENCY);
+    anyhow::ensure!(concurrency > 0, "restore concurrency must be at least 1");
+    let doc = storage
+        .get_root_metadata()
+        .await?
+        .context("root document not found")?;
+    let blob = doc
+        .select(&version)
+        .with_context(|| format!("version {version} not found"))?;
+    let size = blob.size();
+    let file = RestoreOutput::open(
+        output_path,
+        size,
+        options.in_place,
+        options.force,
+        options.direct,
+    )?;
+    if file.is_block_device() {
+        info!("Restoring to block device {}", output_path.display());
+    }
+    let file = Arc::new(file);
 
-    let fetch_task = tokio::spawn(async move {
-        let doc = storage
-            .get_root_metadata()
-            .await?
-            .context("root document not found")?;
-        for chunk_hash in doc.current().chunk_hashes() {
-            let chunk_data = storage.get_chunk(&chunk_hash).await?;
-            if chunk_hash != blake3::hash(&chunk_data) {
-                anyhow::bail!("hash didn't match, storage server error");
+    // chunks that have to be written, with their offset and length
+    let (needed_tx, mut needed_rx) = mpsc::channel(CHANNEL_SIZE);
+    let (in_place, sparse) = (options.in_place, options.sparse);
+    let compare_file = file.clone();
+    let chunk_id = storage.chunk_hasher();
+    let compare_task = tokio::task::spawn_blocking(move || {
+        let existing_len = compare_file.current_len()?;
+        let (mut offset, mut reused) = (0, 0);
+        let mut buf = Vec::new();
+        for (hash, len) in blob.chunks() {
+            let chunk_offset = offset;
+            offset += len;
+            // a regular file reads as zeros past its end, `finish` extends it with a hole
+            if hash == ZERO_CHUNK && !compare_file.is_block_device() && chunk_offset >= existing_len
+            {
+                continue;
+            }
+            if hash == ZERO_CHUNK && sparse && compare_file.punch_hole(chunk_offset, len)? {
+                continue;
+            }
+            if in_place && offset <= existing_len {
+                buf.resize(len as usize, 0);
+                compare_file.read_exact_at(&mut buf, chunk_offset)?;
+                let in_place = match hash == ZERO_CHUNK {
+                    true => is_zero_chunk(&buf),
+                    false => chunk_id(&buf) == hash,
+                };
+                if in_place {
+                    reused += 1;
+                    continue;
+                }
+            }
+            if needed_tx.blocking_send((hash, chunk_offset, len)).is_err() {
+                // the fetch task failed and reports why
+                return Ok(());
             }
-            chunk_tx.send(chunk_data).await?;
+        }
+        if in_place {
+            info!(
+                "{reused} of {} chunks were already in place",
+                blob.chunk_count()
+            );
         }
         anyhow::Ok(())
     });
 
-    let output_path = output_path.to_owned();
-    let write_task = tokio::task::spawn_blocking(move || {
-        let mut file = std::fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(output_path)?;
-
-        while let Some(chunk_data) = chunk_rx.blocking_recv() {
-            use std::io::Write;
-            file.write_all(&chunk_data)?;
+    // chunks complete in any order, each is written at its own offset. Only `concurrency`
+    // chunks are held in memory at a time.
+    let fetch_file = file.clone();
+    let fetch_task = tokio::spawn(async move {
+        let semaphore = Arc::new(Semaphore::new(concurrency));
+        let mut join_set = JoinSet::new();
+        while let Some((chunk_hash, offset, len)) = needed_rx.recv().await {
+            let permit = semaphore.clone().acquire_owned().await?;
+            while let Some(result) = join_set.try_join_next() {
+                result??;
+            }
+            let storage = storage.clone();
+            let file = fetch_file.clone();
+            join_set.spawn(async move {
+                let _permit = permit;
+                let chunk_data = fetch_chunk(&storage, &chunk_hash, len).await?;
+                tokio::task::spawn_blocking(move || {
+                    // zero chunks of versions backed up before they got a marker, marked
+                    // ones only get here if punching failed already
+                    let zero = chunk_hash != ZERO_CHUNK && is_zero_chunk(&chunk_data);
+                    if sparse && zero && file.punch_hole(offset, len)? {
+                        return Ok(());
+                    }
+                    file.write_all_at(&chunk_data, offset)
+                })
+                .await??;
+                anyhow::Ok(())
+            });
+        }
+        while let Some(result) = join_set.join_next().await {
+            result??;
         }
-
-        file.flush()?;
         anyhow::Ok(())
     });
 
-    let (fetch_result, write_result) = tokio::try_join!(fetch_task, write_task)?;
+    let (compare_result, fetch_result) = tokio::try_join!(compare_task, fetch_task)?;
     fetch_result?;
-    write_result?;
+    compare_result?;
 
+    // an in place target may have been longer
+    tokio::task::spawn_blocking(move || file.finish(size)).await??;
     Ok(())
 }
 
-pub async fn gc(storage: Storage) -> anyhow::Result<()> {
-    let (doc, available_hashes) =
-        tokio::try_join!(storage.get_root_metadata(), storage.available_hashes())?;
-    let doc = doc.context("root document not found")?;
+// Chunk data checked against its hash, zero chunks are produced without fetching
+pub(crate) async fn fetch_chunk(
+    storage: &Storage,
+    hash: &blake3::Hash,
+    len: u64,
+) -> anyhow::Result<Vec<u8>> {
+    if *hash == ZERO_CHUNK {
+        return Ok(vec![0; len as usize]);
+    }
+    let data = storage.get_chunk(hash).await?;
+    anyhow::ensure!(
+        *hash == storage.chunk_id(&data),
+        "hash didn't match, storage server error"
+    );
+    Ok(data)
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct GcOptions {
+    // Two-phase mode: only delete chunks that a previous gc run at least this long ago
+    // already found unreferenced, and that were not written within this period either.
+    pub grace_period: Option<chrono::Duration>,
+    // Packs whose live fraction falls below this are rewritten without their dead chunks,
+    // otherwise only packs without any live chunk are deleted.
+    pub repack_threshold: Option<f64>,
+}
+
+pub async fn gc(storage: Storage, options: &GcOptions) -> anyhow::Result<()> {
+    // deleting chunks is only safe while no backup may be relying on them
+    let lock_storage = storage.clone();
+    with_lock(
+        &lock_storage,
+        LockKind::Exclusive,
+        "gc",
+        gc_locked(storage, options),
+    )
+    .await
+}
+
+async fn gc_locked(storage: Storage, options: &GcOptions) -> anyhow::Result<()> {
+    // its chunks would look unreferenced until it is moved to the default set
+    anyhow::ensure!(
+        !storage.has_legacy_root().await?,
+        "repository has a root document from before backup sets, run info to migrate it first"
+    );
+    let (sets, checkpoints, chunks) = tokio::try_join!(
+        storage.list_sets(),
+        storage.list_checkpoints(),
+        storage.list_chunks()
+    )?;
+    anyhow::ensure!(
+        !sets.is_empty() || !checkpoints.is_empty(),
+        "no backup sets found"
+    );
     // mark all as deletable first
-    let mut hashes_to_delete = available_hashes
-        .into_iter()
-        .map(<[u8; 32]>::from)
-        .collect::<BTreeSet<_>>();
-    for hash in doc
-        .current()
-        .chunk_hashes()
-        .into_iter()
-        .chain(doc.versions().flat_map(|x| x.unique_chunk_hashes()))
-    {
-        if !hashes_to_delete.remove(hash.as_bytes()) {
-            error!("hash referenced by document is present: {}", hash);
+    let mut hashes_to_delete = BTreeMap::new();
+    for chunk in &chunks {
+        // a chunk can be stored both loose and packed, it is as young as its newest copy
+        let modified = hashes_to_delete
+            .entry(<[u8; 32]>::from(chunk.hash))
+            .or_insert(chunk.last_modified);
+        *modified = chunk.last_modified.max(*modified);
+    }
+    // sets share chunks, so collect references of all of them first
+    let mut referenced = BTreeSet::new();
+    for set in sets {
+        let doc = storage
+            .with_set(&set)?
+            .get_root_metadata()
+            .await?
+            .with_context(|| format!("root document of set {set} not found"))?;
+        referenced.extend(
+            doc.current()
+                .chunk_hashes()
+                .chain(doc.versions().flat_map(|x| x.unique_chunk_hashes()))
+                .map(<[u8; 32]>::from),
+        );
+    }
+    // interrupted backups keep their chunks until they are resumed or replaced
+    for set in checkpoints {
+        if let Some(checkpoint) = storage.with_set(&set)?.get_checkpoint().await? {
+            referenced.extend(checkpoint.blob.chunk_hashes().map(<[u8; 32]>::from));
+        }
+    }
+    for hash in referenced {
+        if hashes_to_delete.remove(&hash).is_none() {
+            let hash = blake3::Hash::from_bytes(hash);
+            error!("hash referenced by document is not present: {}", hash);
         }
     }
-    let delete_count = hashes_to_delete.len();
-    storage.delete_chunks(hashes_to_delete).await?;
+
+    let now = chrono::Utc::now();
+    let mut cutoff = now;
+    if let Some(grace_period) = options.grace_period {
+        cutoff = now - grace_period;
+        let prev_mark = storage.get_gc_mark().await?;
+        if let Some(mark) = prev_mark.as_ref().filter(|x| x.timestamp() > cutoff) {
+            // replacing a young mark would keep pushing deletion out forever
+            info!(
+                "Previous gc mark from {} is within the grace period, not deleting",
+                mark.timestamp()
+            );
+            return Ok(());
+        }
+        let prev_candidates = prev_mark
+            .map(|x| x.candidates.into_iter().collect::<BTreeSet<_>>())
+            .unwrap_or_default();
+        let (deletable, pending): (BTreeMap<_, _>, BTreeMap<_, _>) = hashes_to_delete
+            .into_iter()
+            .partition(|(hash, modified)| prev_candidates.contains(hash) && *modified <= cutoff);
+        hashes_to_delete = deletable;
+        info!(
+            "Marked {} unreferenced chunks for a later gc",
+            pending.len()
+        );
+        storage
+            .put_gc_mark(&GcMark {
+                timestamp: now.timestamp(),
+                candidates: pending.into_keys().collect(),
+            })
+            .await?;
+    }
+
+    // chunk caches elsewhere must not trust their list once anything may be deleted
+    let generation = storage.bump_generation().await?;
+    let loose = chunks
+        .iter()
+        .filter(|x| x.pack.is_none())
+        .map(|x| <[u8; 32]>::from(x.hash))
+        .filter(|x| hashes_to_delete.contains_key(x))
+        .collect::<BTreeSet<_>>();
+    let delete_count = loose.len();
+    storage.delete_chunks(loose).await?;
     info!("Deleted {delete_count} chunks");
+    gc_packs(&storage, &chunks, &hashes_to_delete, cutoff, options).await?;
+
+    let remaining = chunks
+        .iter()
+        .map(|x| <[u8; 32]>::from(x.hash))
+        .filter(|x| !hashes_to_delete.contains_key(x))
+        .collect();
+    ChunkCache::for_generation(&storage, generation, remaining)
+        .save()
+        .await
+}
+
+// Deletes packs without live chunks and rewrites sparse ones, new packs are written
+// before the old ones are deleted
+async fn gc_packs(
+    storage: &Storage,
+    chunks: &[ChunkMeta],
+    deletable: &BTreeMap<[u8; 32], chrono::DateTime<chrono::Utc>>,
+    cutoff: chrono::DateTime<chrono::Utc>,
+    options: &GcOptions,
+) -> anyhow::Result<()> {
+    let indexed = chunks
+        .iter()
+        .filter_map(|x| x.pack.as_deref())
+        .collect::<BTreeSet<_>>();
+    // packs without an index are left behind by interrupted uploads
+    for pack in storage.list_packs().await? {
+        if !indexed.contains(pack.id.as_str()) && pack.last_modified <= cutoff {
+            info!(pack = pack.id, "Deleting unindexed pack");
+            storage.delete_pack(&pack.id).await?;
+        }
+    }
+
+    let mut builder = PackBuilder::new(storage.pack_size().unwrap_or(DEFAULT_PACK_SIZE));
+    let mut written = BTreeSet::new();
+    let mut replaced = Vec::new();
+    for id in indexed {
+        let index = storage.get_pack_index(id).await?;
+        let total_size: u64 = index.entries.iter().map(|x| u64::from(x.len)).sum();
+        let live = index
+            .entries
+            .iter()
+            .filter(|x| !deletable.contains_key(&x.hash))
+            .collect::<Vec<_>>();
+        let live_size: u64 = live.iter().map(|x| u64::from(x.len)).sum();
+        let sparse = options
+            .repack_threshold
+            .is_some_and(|threshold| (live_size as f64) < threshold * total_size as f64);
+        if !live.is_empty() && (live.len() == index.entries.len() || !sparse) {
+            continue;
+        }
+        if !live.is_empty() {
+            info!(
+                pack = id,
+                "Repacking {} of {} chunks",
+                live.len(),
+                index.entries.len()
+            );
+            let data = storage.get_pack(id).await?;
+            for entry in live {
+                let hash = blake3::Hash::from_bytes(entry.hash);
+                if let Some(pack) = builder.add(hash, &data[entry.range()]) {
+                    written.insert(storage.put_pack(pack).await?);
+                }
+            }
+        }
+        replaced.push(id);
+    }
+    if let Some(pack) = builder.finish() {
+        written.insert(storage.put_pack(pack).await?);
+    }
+    // a rewritten pack can have the same content, and so the same id, as a replaced one
+    let replaced = replaced
+        .into_iter()
+        .filter(|id| !written.contains(*id))
+        .collect::<Vec<_>>();
+    for id in &replaced {
+        storage.delete_pack(id).await?;
+    }
+    info!(
+        "Replaced {} packs with {} new ones",
+        replaced.len(),
+        written.len()
+    );
     Ok(())
 }
+
+// Prune and forget only rewrite the root with compare-and-swap, chunks are left to gc
+pub async fn prune(storage: Storage, policy: &RetentionPolicy) -> anyhow::Result<()> {
+    let lock_storage = storage.clone();
+    let operation = prune_locked(storage, policy);
+    with_lock(&lock_storage, LockKind::Shared, "prune", operation).await
+}
+
+async fn prune_locked(storage: Storage, policy: &RetentionPolicy) -> anyhow::Result<()> {
+    anyhow::ensure!(!policy.is_empty(), "no retention rules given");
+    let mut removed = 0;
+    update_root(&storage, |doc| {
+        let mut doc = doc.context("root document not found")?;
+        removed = policy.apply(&mut doc);
+        Ok((removed > 0).then_some(doc))
+    })
+    .await?;
+    info!(set = storage.set_name(), "Pruned {removed} versions");
+    Ok(())
+}
+
+pub async fn forget(storage: Storage, version: VersionSpec) -> anyhow::Result<()> {
+    let lock_storage = storage.clone();
+    let operation = forget_locked(storage, version);
+    with_lock(&lock_storage, LockKind::Shared, "forget", operation).await
+}
+
+async fn forget_locked(storage: Storage, version: VersionSpec) -> anyhow::Result<()> {
+    let (doc, root_version) = storage.get_root_metadata_for_update().await?;
+    let mut doc = doc.context("root document not found")?;
+    let n = doc
+        .resolve(&version)
+        .with_context(|| format!("version {version} not found"))?;
+    doc.forget(n)?;
+    // version numbers may mean something else after a concurrent change, so don't retry
+    storage
+        .put_root_metadata(doc, &root_version)
+        .await
+        .context("cannot forget version, try again")?;
+    info!(set = storage.set_name(), "Forgot version {n}");
+    Ok(())
+}
+
+// Deletes expired locks, or every lock with `all`. Returns the broken locks.
+pub async fn break_locks(storage: Storage, all: bool) -> anyhow::Result<Vec<lock::LockInfo>> {
+    let mut broken = Vec::new();
+    for lock in storage.list_locks().await? {
+        if all || lock.is_expired() {
+            storage.delete_lock(lock.holder()).await?;
+            broken.push(lock);
+        }
+    }
+    Ok(broken)
+}
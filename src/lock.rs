@@ -0,0 +1,169 @@
+use crate::storage::Storage;
+use bincode::{Decode, Encode};
+use chrono::{DateTime, Duration, Utc};
+use std::fmt;
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::{error, warn};
+
+// Locks expire unless refreshed, so a crashed process doesn't block the repository forever
+pub const LOCK_TTL: Duration = Duration::minutes(10);
+const LOCK_REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+// a lock that couldn't be refreshed is given up this long before others may take it over
+const LOCK_EXPIRY_MARGIN: Duration = Duration::minutes(2);
+
+#[derive(Encode, Decode, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockKind {
+    // operations that only add chunks or read them, any number can run at once
+    Shared,
+    // operations that delete chunks, nothing else may run at the same time
+    Exclusive,
+}
+
+// Contents of a lock object stored in the repository
+#[derive(Encode, Decode, Clone, Debug)]
+pub struct LockInfo {
+    holder: String,
+    hostname: String,
+    pid: u32,
+    kind: LockKind,
+    operation: String,
+    expires: i64,
+}
+
+impl LockInfo {
+    pub fn new(kind: LockKind, operation: &str, ttl: Duration) -> Self {
+        Self {
+            holder: format!("{:016x}", rand::random::<u64>()),
+            hostname: hostname(),
+            pid: std::process::id(),
+            kind,
+            operation: operation.to_owned(),
+            expires: (Utc::now() + ttl).timestamp(),
+        }
+    }
+    pub fn holder(&self) -> &str {
+        &self.holder
+    }
+    pub fn kind(&self) -> LockKind {
+        self.kind
+    }
+    pub fn expires(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp(self.expires, 0).unwrap()
+    }
+    pub fn is_expired(&self) -> bool {
+        self.expires < Utc::now().timestamp()
+    }
+    fn conflicts_with(&self, other: &LockInfo) -> bool {
+        self.holder != other.holder
+            && !other.is_expired()
+            && (self.kind == LockKind::Exclusive || other.kind == LockKind::Exclusive)
+    }
+}
+
+impl fmt::Display for LockInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} lock {} held by {} on {} (pid {}), expires {}",
+            self.kind,
+            self.holder,
+            self.operation,
+            self.hostname,
+            self.pid,
+            self.expires()
+        )
+    }
+}
+
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for its whole length
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+    if ret != 0 {
+        return "unknown".to_owned();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+// A held repository lock, kept alive by a heartbeat until released
+pub struct RepoLock {
+    storage: Storage,
+    holder: String,
+    heartbeat: JoinHandle<()>,
+    // set once another process may hold a conflicting lock
+    lost: watch::Receiver<bool>,
+}
+
+impl RepoLock {
+    pub async fn acquire(
+        storage: &Storage,
+        kind: LockKind,
+        operation: &str,
+    ) -> anyhow::Result<Self> {
+        let mut info = LockInfo::new(kind, operation, LOCK_TTL);
+        // write first and check after, so two racing exclusive lockers both back off
+        let mut version = storage.put_lock(&info).await?;
+        let locks = storage.list_locks().await?;
+        if let Some(other) = locks.iter().find(|other| info.conflicts_with(other)) {
+            storage.delete_lock(&info.holder).await?;
+            anyhow::bail!("repository is locked: {other}, use break-lock if it is stale");
+        }
+
+        let (lost_tx, lost) = watch::channel(false);
+        let holder = info.holder.clone();
+        let heartbeat = tokio::spawn({
+            let storage = storage.clone();
+            let holder = info.holder.clone();
+            async move {
+                loop {
+                    tokio::time::sleep(LOCK_REFRESH_INTERVAL).await;
+                    let expires = info.expires();
+                    info.expires = (Utc::now() + LOCK_TTL).timestamp();
+                    match storage.refresh_lock(&info, &version).await {
+                        Ok(Some(new_version)) => version = new_version,
+                        Ok(None) => {
+                            error!(holder, "Repository lock was broken by someone else");
+                            break;
+                        }
+                        Err(e) => {
+                            warn!(holder, "Failed to refresh repository lock: {e:#}");
+                            info.expires = expires.timestamp();
+                            if Utc::now() + LOCK_EXPIRY_MARGIN >= expires {
+                                error!(holder, "Repository lock is about to expire, giving up");
+                                break;
+                            }
+                        }
+                    }
+                }
+                let _ = lost_tx.send(true);
+            }
+        });
+        Ok(Self {
+            storage: storage.clone(),
+            holder,
+            heartbeat,
+            lost,
+        })
+    }
+
+    // Completes once the lock is lost, the operation holding it must stop then
+    pub async fn lost(&self) {
+        let mut lost = self.lost.clone();
+        if lost.wait_for(|x| *x).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    // Stops the heartbeat and deletes the lock, fails if the lock was lost meanwhile
+    pub async fn release(self) -> anyhow::Result<()> {
+        self.heartbeat.abort();
+        let _ = self.heartbeat.await;
+        anyhow::ensure!(
+            !*self.lost.borrow(),
+            "repository lock {} was lost while the operation was running",
+            self.holder
+        );
+        self.storage.delete_lock(&self.holder).await
+    }
+}
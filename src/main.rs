@@ -1,9 +1,26 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use anyhow::Context;
-use bup::storage::Storage;
+use bup::{
+    blob::VersionSpec,
+    changes::ChangedRegions,
+    check::CheckMode,
+    chunker::Chunker,
+    codec::Compression,
+    crypto::EncryptionConfig,
+    nbd::{Listen, NbdOptions},
+    retention::RetentionPolicy,
+    storage::{RepoConfig, Storage, DEFAULT_SET},
+    BackupOptions, GcOptions, RestoreOptions,
+};
 use clap::{Args, Parser, Subcommand};
-use object_store::{aws::AmazonS3Builder, local::LocalFileSystem};
+use object_store::{
+    aws::{AmazonS3Builder, AmazonS3ConfigKey, S3ConditionalPut},
+    local::LocalFileSystem,
+};
 use tracing::info;
 
 #[derive(Args)]
@@ -20,20 +37,227 @@ struct BackendOpts {
 struct Cli {
     #[command(flatten)]
     backend: BackendOpts,
+    /// Backup set to operate on, all sets share one chunk pool
+    #[arg(long, global = true)]
+    set: Option<String>,
+    /// Compression of uploaded chunks, `none`, `zstd` or `zstd:<level>`
+    #[arg(long, global = true, default_value_t = Compression::None)]
+    compression: Compression,
+    /// Key of an encrypted repository, the passphrase can also be set in `BUP_PASSPHRASE`
+    #[arg(long, global = true)]
+    key_file: Option<PathBuf>,
+    /// Upload chunks in packs of about this size instead of one object each, e.g. `64M`
+    #[arg(long, global = true, value_parser = parse_size)]
+    pack_size: Option<u64>,
+    /// Where the list of known chunks is cached, defaults to `$XDG_CACHE_HOME/bup`
+    #[arg(long, global = true)]
+    cache_dir: Option<PathBuf>,
+    /// List the whole bucket instead of using the local chunk cache
+    #[arg(long, global = true, conflicts_with = "cache_dir")]
+    no_cache: bool,
     #[command(subcommand)]
     command: Commands,
 }
 #[derive(Subcommand)]
 enum Commands {
+    /// Create the repository config, encrypted if a key is given
+    Init {
+        #[arg(long, default_value_t = Chunker::DEFAULT)]
+        chunker: Chunker,
+        /// Chunk size, the average size for cdc, e.g. `4M` or `64K`
+        #[arg(long, value_parser = parse_chunk_size)]
+        chunk_size: Option<u32>,
+    },
     Backup {
         #[arg(long)]
         file: PathBuf,
+        /// Chunking of a new set, `fixed` for block images or `cdc` for shifting data
+        #[arg(long)]
+        chunker: Option<Chunker>,
+        /// Chunk size of a new set, the average size for cdc, e.g. `4M`
+        #[arg(long, value_parser = parse_chunk_size)]
+        chunk_size: Option<u32>,
+        /// Only read these `<offset> <length>` byte ranges, one per line
+        #[arg(long, conflicts_with = "changed_bitmap")]
+        changed_regions: Option<PathBuf>,
+        /// Only read blocks whose bit is set, least significant bit first
+        #[arg(long, requires = "bitmap_block_size")]
+        changed_bitmap: Option<PathBuf>,
+        /// Bytes per bit of --changed-bitmap, e.g. `64K`
+        #[arg(long, value_parser = parse_size)]
+        bitmap_block_size: Option<u64>,
+        /// Ignore the changed regions on every n-th version
+        #[arg(long)]
+        full_pass_every: Option<usize>,
+        /// Continue an interrupted backup of the unchanged source from its last checkpoint
+        #[arg(long)]
+        resume: bool,
+        /// Bytes read between checkpoints, e.g. `4G`
+        #[arg(long, value_parser = parse_size)]
+        checkpoint_interval: Option<u64>,
     },
     Restore {
         #[arg(long)]
         output: PathBuf,
+        /// Version number, RFC 3339 timestamp, `latest` or `latest~k`
+        #[arg(long, default_value_t = VersionSpec::LATEST)]
+        version: VersionSpec,
+        /// Update an existing output, only writing chunks that differ
+        #[arg(long)]
+        in_place: bool,
+        /// Number of chunks fetched at the same time
+        #[arg(long, default_value_t = bup::RESTORE_CONCURRENCY)]
+        concurrency: usize,
+        /// Overwrite an existing non-empty output file or block device
+        #[arg(long)]
+        force: bool,
+        /// Write with O_DIRECT, bypassing the page cache
+        #[arg(long)]
+        direct: bool,
+        /// Punch holes for all-zero chunks instead of writing zeros over existing data
+        #[arg(long)]
+        sparse: bool,
     },
     Info {},
+    Gc {
+        #[command(flatten)]
+        gc: GcArgs,
+    },
+    /// Drop old versions according to retention rules, all sets unless --set is given
+    Prune {
+        #[arg(long, default_value_t = 0)]
+        keep_last: usize,
+        #[arg(long, default_value_t = 0)]
+        keep_hourly: usize,
+        #[arg(long, default_value_t = 0)]
+        keep_daily: usize,
+        #[arg(long, default_value_t = 0)]
+        keep_weekly: usize,
+        #[arg(long, default_value_t = 0)]
+        keep_monthly: usize,
+        #[arg(long, default_value_t = 0)]
+        keep_yearly: usize,
+        /// Total size old versions may retain, e.g. `500G`
+        #[arg(long, value_parser = parse_size)]
+        max_retained_size: Option<u64>,
+        /// Run gc afterwards to delete chunks no longer referenced
+        #[arg(long)]
+        gc: bool,
+        #[command(flatten)]
+        gc_args: GcArgs,
+    },
+    /// Drop a single version, older versions stay restorable
+    Forget {
+        /// Version number, RFC 3339 timestamp, `latest` or `latest~k`
+        version: VersionSpec,
+    },
+    /// Verify that all versions reconstruct and every referenced chunk exists
+    Check {
+        /// Download and re-hash every referenced chunk
+        #[arg(long)]
+        full: bool,
+        /// Download and re-hash a random sample of chunks, e.g. `5%`
+        #[arg(long, value_parser = parse_percent, conflicts_with = "full")]
+        sample: Option<f64>,
+    },
+    /// Serve a version over the NBD protocol, read-only unless --overlay is given
+    ServeNbd {
+        /// Version number, RFC 3339 timestamp, `latest` or `latest~k`
+        #[arg(long, default_value_t = VersionSpec::LATEST)]
+        version: VersionSpec,
+        /// `unix:<path>` or `<host>:<port>`
+        #[arg(long)]
+        listen: Listen,
+        /// Accept writes into this new local file, it is removed when the server stops
+        #[arg(long)]
+        overlay: Option<PathBuf>,
+    },
+    /// Remove stale repository locks left behind by crashed processes
+    BreakLock {
+        /// Also remove locks that have not expired yet
+        #[arg(long)]
+        all: bool,
+    },
+}
+
+#[derive(Args)]
+struct GcArgs {
+    /// Only delete chunks a gc run at least this many hours ago already found unreferenced
+    #[arg(long)]
+    grace_hours: Option<u32>,
+    /// Rewrite packs with less than this fraction of live data, e.g. `50%`
+    #[arg(long, value_parser = parse_percent)]
+    repack_below: Option<f64>,
+}
+
+impl GcArgs {
+    fn options(&self) -> GcOptions {
+        GcOptions {
+            grace_period: self
+                .grace_hours
+                .map(|hours| chrono::Duration::hours(hours.into())),
+            repack_threshold: self.repack_below,
+        }
+    }
+}
+
+// Keyfile contents or passphrase, both go through the same key derivation
+fn read_secret(key_file: Option<&Path>) -> anyhow::Result<Option<Vec<u8>>> {
+    if let Some(path) = key_file {
+        let secret = std::fs::read(path)
+            .with_context(|| format!("failed to read key file {}", path.display()))?;
+        anyhow::ensure!(!secret.is_empty(), "key file {} is empty", path.display());
+        return Ok(Some(secret));
+    }
+    Ok(std::env::var_os("BUP_PASSPHRASE").map(|x| x.into_encoded_bytes()))
+}
+
+fn default_cache_dir() -> Option<PathBuf> {
+    let cache_home = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+    Some(cache_home.join("bup"))
+}
+
+fn parse_percent(s: &str) -> anyhow::Result<f64> {
+    let percent: f64 = s
+        .trim_end_matches('%')
+        .parse()
+        .with_context(|| format!("invalid percentage {s:?}"))?;
+    anyhow::ensure!(
+        (0.0..=100.0).contains(&percent),
+        "percentage must be between 0 and 100"
+    );
+    Ok(percent / 100.0)
+}
+
+fn parse_chunk_size(s: &str) -> anyhow::Result<u32> {
+    Ok(parse_size(s)?.try_into()?)
+}
+
+// Parses sizes like `4096`, `64K`, `4MiB` or `2T`, units are binary
+fn parse_size(s: &str) -> anyhow::Result<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("invalid size {s:?}"))?;
+    let shift = match unit
+        .trim()
+        .to_ascii_uppercase()
+        .trim_end_matches(['B', 'I'])
+    {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => anyhow::bail!("invalid size unit in {s:?}"),
+    };
+    number
+        .checked_mul(1 << shift)
+        .with_context(|| format!("size {s:?} is too large"))
 }
 
 #[tokio::main]
@@ -54,42 +278,223 @@ pub async fn main() -> anyhow::Result<()> {
             test_fs_backend: None,
             s3: true,
         } => {
-            let storage = AmazonS3Builder::from_env().build()?;
+            let mut builder = AmazonS3Builder::from_env();
+            // root documents are updated with If-Match unless configured otherwise
+            if builder
+                .get_config_value(&AmazonS3ConfigKey::ConditionalPut)
+                .is_none()
+            {
+                builder = builder.with_conditional_put(S3ConditionalPut::ETagMatch);
+            }
+            let storage = builder.build()?;
             Storage::new(Arc::new(storage))?
         }
         _ => unreachable!("Backend options are mutually exclusive"),
+    }
+    .with_compression(cli.compression);
+    let storage = match cli.pack_size {
+        Some(size) => storage.with_pack_size(size.try_into()?),
+        None => storage,
+    };
+    let storage = match (cli.no_cache, cli.cache_dir.or_else(default_cache_dir)) {
+        (false, Some(dir)) => storage.with_cache_dir(dir),
+        _ => storage,
+    };
+    let secret = read_secret(cli.key_file.as_deref())?;
+    let storage = match cli.command {
+        Commands::Init { .. } => storage,
+        _ => storage.unlock(secret.as_deref()).await?,
     };
+    let set_storage = storage.with_set(cli.set.as_deref().unwrap_or(DEFAULT_SET))?;
 
     match cli.command {
-        Commands::Backup { file } => {
+        Commands::Init {
+            chunker,
+            chunk_size,
+        } => {
+            let chunker = chunk_size.map_or(chunker, |size| chunker.with_chunk_size(size));
+            let encryption = match &secret {
+                Some(secret) => Some(EncryptionConfig::generate(secret)?.0),
+                None => None,
+            };
+            let encrypted = encryption.is_some();
+            storage
+                .init_config(&RepoConfig {
+                    chunker,
+                    encryption,
+                })
+                .await?;
+            println!("Initialized repository with {chunker} chunking, encrypted: {encrypted}");
+        }
+        Commands::Backup {
+            file,
+            chunker,
+            chunk_size,
+            changed_regions,
+            changed_bitmap,
+            bitmap_block_size,
+            full_pass_every,
+            resume,
+            checkpoint_interval,
+        } => {
             info!("Starting backup of file: {}", file.display());
-            bup::backup(storage, &file).await?;
+            let changed_regions = match (changed_regions, changed_bitmap, bitmap_block_size) {
+                (Some(path), _, _) => Some(ChangedRegions::from_file(&path)?),
+                (None, Some(path), Some(block_size)) => {
+                    Some(ChangedRegions::from_bitmap_file(&path, block_size)?)
+                }
+                _ => None,
+            };
+            let options = BackupOptions {
+                chunker,
+                chunk_size,
+                changed_regions,
+                full_pass_every,
+                resume,
+                checkpoint_interval,
+            };
+            bup::backup_with_options(set_storage, &file, &options).await?;
             info!("Backup completed");
         }
-        Commands::Restore { output } => {
-            info!("Starting restore to: {}", output.display());
-            bup::restore(storage, &output).await?;
+        Commands::Restore {
+            output,
+            version,
+            in_place,
+            concurrency,
+            force,
+            direct,
+            sparse,
+        } => {
+            info!(
+                "Starting restore of version {version} to: {}",
+                output.display()
+            );
+            let options = RestoreOptions {
+                in_place,
+                concurrency: Some(concurrency),
+                force,
+                direct,
+                sparse,
+            };
+            bup::restore_with_options(set_storage, &output, version, &options).await?;
             info!("Restore completed");
         }
         Commands::Info {} => {
             info!("Getting version history");
-            let metadata = storage
-                .get_root_metadata()
-                .await?
-                .context("root is not present")?;
+            let sets = match cli.set {
+                Some(set) => vec![set],
+                None => storage.list_sets().await?,
+            };
+            if let Some(config) = storage.get_config().await? {
+                println!("Chunking of new sets: {}", config.chunker);
+            }
+            anyhow::ensure!(!sets.is_empty(), "no backup sets found");
+            for set in sets {
+                let metadata = storage
+                    .with_set(&set)?
+                    .get_root_metadata()
+                    .await?
+                    .with_context(|| format!("root of set {set} is not present"))?;
 
-            let current = metadata.current();
-            println!(
-                "Size: {}",
-                humansize::format_size(current.size(), humansize::BINARY)
-            );
-            println!("Last updated: {}", current.timestamp());
-            for version in metadata.versions() {
+                let current = metadata.current();
+                println!("Set: {set}");
+                println!("Chunking: {}", metadata.chunker());
                 println!(
-                    "Old Version from: {}, retained size: {}",
-                    version.timestamp(),
-                    humansize::format_size(version.retained_size(), humansize::BINARY),
+                    "Size: {}",
+                    humansize::format_size(current.size(), humansize::BINARY)
                 );
+                println!("Last updated: {}", current.timestamp());
+                for (n, version) in (0..metadata.version_count() - 1)
+                    .rev()
+                    .zip(metadata.versions())
+                {
+                    println!(
+                        "Old Version {n} from: {}, retained size: {}",
+                        version.timestamp(),
+                        humansize::format_size(version.retained_size(), humansize::BINARY),
+                    );
+                }
+            }
+        }
+        Commands::Gc { gc } => {
+            info!("Starting garbage collection");
+            bup::gc(storage, &gc.options()).await?;
+            info!("Garbage collection completed");
+        }
+        Commands::Prune {
+            keep_last,
+            keep_hourly,
+            keep_daily,
+            keep_weekly,
+            keep_monthly,
+            keep_yearly,
+            max_retained_size,
+            gc,
+            gc_args,
+        } => {
+            let policy = RetentionPolicy {
+                keep_last,
+                keep_hourly,
+                keep_daily,
+                keep_weekly,
+                keep_monthly,
+                keep_yearly,
+                max_retained_size,
+            };
+            let sets = match cli.set {
+                Some(set) => vec![set],
+                None => storage.list_sets().await?,
+            };
+            for set in sets {
+                info!("Pruning set {set}");
+                bup::prune(storage.with_set(&set)?, &policy).await?;
+            }
+            if gc {
+                info!("Starting garbage collection");
+                bup::gc(storage, &gc_args.options()).await?;
+                info!("Garbage collection completed");
+            }
+        }
+        Commands::Forget { version } => {
+            bup::forget(set_storage, version).await?;
+        }
+        Commands::Check { full, sample } => {
+            let mode = match (full, sample) {
+                (true, _) => CheckMode::Full,
+                (false, Some(fraction)) => CheckMode::Sample(fraction),
+                (false, None) => CheckMode::Fast,
+            };
+            let report = bup::check::check(storage, mode).await?;
+            println!(
+                "Checked {} versions, {} chunks, downloaded {}",
+                report.checked_versions, report.checked_chunks, report.downloaded_chunks
+            );
+            for hash in &report.missing_chunks {
+                println!("Missing chunk: {hash}");
+            }
+            for hash in &report.corrupt_chunks {
+                println!("Corrupt chunk: {hash}");
+            }
+            for (set, reason) in &report.corrupt_sets {
+                println!("Corrupt history in set {set}: {reason}");
+            }
+            anyhow::ensure!(report.is_ok(), "repository check found problems");
+        }
+        Commands::ServeNbd {
+            version,
+            listen,
+            overlay,
+        } => {
+            let options = NbdOptions {
+                version,
+                listen,
+                overlay,
+            };
+            bup::nbd::serve_nbd(set_storage, &options).await?;
+        }
+        Commands::BreakLock { all } => {
+            for lock in bup::break_locks(storage, all).await? {
+                println!("Removed {lock}");
             }
         }
     }
@@ -0,0 +1,802 @@
+use crate::{
+    blob::VersionSpec, lock::LockKind, reader::VersionChunks, storage::Storage, with_lock,
+};
+use anyhow::Context;
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::{fmt, str::FromStr};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, WriteHalf};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+
+// Constants of the NBD protocol, see
+// https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943;
+const IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+const OPTION_REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+const STRUCTURED_REPLY_MAGIC: u32 = 0x668e_33ef;
+
+const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const FLAG_NO_ZEROES: u16 = 1 << 1;
+const CLIENT_FIXED_NEWSTYLE: u32 = 1 << 0;
+const CLIENT_NO_ZEROES: u32 = 1 << 1;
+
+const TRANSMISSION_HAS_FLAGS: u16 = 1 << 0;
+const TRANSMISSION_READ_ONLY: u16 = 1 << 1;
+const TRANSMISSION_SEND_WRITE_ZEROES: u16 = 1 << 6;
+const TRANSMISSION_CAN_MULTI_CONN: u16 = 1 << 8;
+
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_ABORT: u32 = 2;
+const OPT_LIST: u32 = 3;
+const OPT_INFO: u32 = 6;
+const OPT_GO: u32 = 7;
+const OPT_STRUCTURED_REPLY: u32 = 8;
+const OPT_LIST_META_CONTEXT: u32 = 9;
+const OPT_SET_META_CONTEXT: u32 = 10;
+
+const REP_ACK: u32 = 1;
+const REP_SERVER: u32 = 2;
+const REP_INFO: u32 = 3;
+const REP_META_CONTEXT: u32 = 4;
+const REP_ERR_UNSUP: u32 = (1 << 31) | 1;
+const REP_ERR_INVALID: u32 = (1 << 31) | 3;
+const REP_ERR_UNKNOWN: u32 = (1 << 31) | 6;
+const INFO_EXPORT: u16 = 0;
+
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+const CMD_TRIM: u16 = 4;
+const CMD_CACHE: u16 = 5;
+const CMD_WRITE_ZEROES: u16 = 6;
+const CMD_BLOCK_STATUS: u16 = 7;
+const CMD_FLAG_REQ_ONE: u16 = 1 << 3;
+
+const REPLY_FLAG_DONE: u16 = 1 << 0;
+const REPLY_TYPE_NONE: u16 = 0;
+const REPLY_TYPE_OFFSET_DATA: u16 = 1;
+const REPLY_TYPE_BLOCK_STATUS: u16 = 5;
+const REPLY_TYPE_ERROR: u16 = (1 << 15) | 1;
+
+const STATE_HOLE: u32 = 1 << 0;
+const STATE_ZERO: u32 = 1 << 1;
+
+const EPERM: u32 = 1;
+const EIO: u32 = 5;
+const EINVAL: u32 = 22;
+
+// the only metadata context, zero chunks are reported as holes
+const ALLOCATION_CONTEXT: &str = "base:allocation";
+const ALLOCATION_CONTEXT_ID: u32 = 1;
+
+const MAX_OPTION_LEN: u32 = 64 * 1024;
+// largest read or write a client may send
+const MAX_REQUEST_LEN: u32 = 32 * 1024 * 1024;
+// requests of one connection handled at the same time
+const CONNECTION_REQUESTS: usize = 16;
+// pause after a failed accept, so a persistent error doesn't spin
+const ACCEPT_RETRY_DELAY: std::time::Duration = std::time::Duration::from_millis(100);
+// granularity of the copy-on-write overlay
+const OVERLAY_BLOCK: u64 = 4096;
+
+// Where the server accepts connections
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Listen {
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+impl FromStr for Listen {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            anyhow::ensure!(!path.is_empty(), "missing unix socket path");
+            return Ok(Self::Unix(path.into()));
+        }
+        let addr = s.strip_prefix("tcp:").unwrap_or(s);
+        anyhow::ensure!(
+            addr.contains(':'),
+            "invalid listen address {s:?}, expected unix:<path> or <host>:<port>"
+        );
+        Ok(Self::Tcp(addr.to_owned()))
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+            Self::Tcp(addr) => write!(f, "{addr}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct NbdOptions {
+    pub version: VersionSpec,
+    pub listen: Listen,
+    // accept writes into this local file instead of serving read-only
+    pub overlay: Option<PathBuf>,
+}
+
+// Serves one version of the set until interrupted, holding a shared lock so gc keeps
+// its chunks
+pub async fn serve_nbd(storage: Storage, options: &NbdOptions) -> anyhow::Result<()> {
+    let lock_storage = storage.clone();
+    with_lock(
+        &lock_storage,
+        LockKind::Shared,
+        "serve-nbd",
+        serve_locked(storage, options),
+    )
+    .await
+}
+
+async fn serve_locked(storage: Storage, options: &NbdOptions) -> anyhow::Result<()> {
+    let doc = storage
+        .get_root_metadata()
+        .await?
+        .context("root document not found")?;
+    let blob = doc
+        .select(&options.version)
+        .with_context(|| format!("version {} not found", options.version))?;
+    let overlay = match &options.overlay {
+        Some(path) => Some(Overlay::create(path, blob.size())?),
+        None => None,
+    };
+    let name = storage.set_name().to_owned();
+    let export = Arc::new(Export::new(
+        name,
+        VersionChunks::new(storage, &blob),
+        overlay,
+    ));
+    info!(
+        "Serving version {} ({} bytes) on {}",
+        options.version,
+        blob.size(),
+        options.listen
+    );
+
+    let mut connections = JoinSet::new();
+    match &options.listen {
+        Listen::Unix(path) => {
+            let listener = UnixListener::bind(path)
+                .with_context(|| format!("failed to listen on {}", path.display()))?;
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => {
+                            connections.spawn(serve_connection(export.clone(), stream));
+                        }
+                        Err(e) => accept_failed(e).await,
+                    },
+                    Some(result) = connections.join_next() => log_connection(result),
+                    _ = tokio::signal::ctrl_c() => break,
+                }
+            }
+            let _ = std::fs::remove_file(path);
+        }
+        Listen::Tcp(addr) => {
+            let listener = TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("failed to listen on {addr}"))?;
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => {
+                            if let Err(e) = stream.set_nodelay(true) {
+                                warn!("Failed to disable Nagle's algorithm: {e}");
+                            }
+                            connections.spawn(serve_connection(export.clone(), stream));
+                        }
+                        Err(e) => accept_failed(e).await,
+                    },
+                    Some(result) = connections.join_next() => log_connection(result),
+                    _ = tokio::signal::ctrl_c() => break,
+                }
+            }
+        }
+    }
+    // the overlay is removed once the last connection lets go of the export
+    connections.shutdown().await;
+    info!("NBD server stopped");
+    Ok(())
+}
+
+// Failed accepts, like running out of descriptors or a client that gave up while queued,
+// don't stop the server
+async fn accept_failed(e: io::Error) {
+    warn!("Failed to accept NBD connection: {e}");
+    // running out of descriptors keeps failing until a connection closes
+    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+}
+
+fn log_connection(result: Result<anyhow::Result<()>, tokio::task::JoinError>) {
+    match result {
+        Ok(Ok(())) => info!("NBD client disconnected"),
+        Ok(Err(e)) => warn!("NBD connection failed: {e:#}"),
+        Err(e) => warn!("NBD connection task failed: {e}"),
+    }
+}
+
+// What the server exposes, shared by all connections
+pub struct Export {
+    name: String,
+    chunks: VersionChunks,
+    overlay: Option<Overlay>,
+}
+
+enum Reply {
+    Done,
+    Data(u64, Vec<u8>),
+    BlockStatus(Vec<(u32, u32)>),
+}
+
+// errno and message sent back for a failed request
+struct RequestError(u32, String);
+
+impl Export {
+    pub fn new(name: String, chunks: VersionChunks, overlay: Option<Overlay>) -> Self {
+        Self {
+            name,
+            chunks,
+            overlay,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.chunks.size()
+    }
+
+    fn transmission_flags(&self) -> u16 {
+        // all connections share the chunk cache and the overlay, so they see the same data
+        let flags = TRANSMISSION_HAS_FLAGS | TRANSMISSION_CAN_MULTI_CONN;
+        match self.overlay {
+            Some(_) => flags | TRANSMISSION_SEND_WRITE_ZEROES,
+            None => flags | TRANSMISSION_READ_ONLY,
+        }
+    }
+
+    // the default export is requested with an empty name
+    fn matches(&self, name: &[u8]) -> bool {
+        name.is_empty() || name == self.name.as_bytes()
+    }
+
+    // Blocks from `pos` on that come from the same layer, up to `end`
+    fn layer_run(&self, pos: u64, end: u64) -> (bool, u64) {
+        let Some(overlay) = &self.overlay else {
+            return (false, end);
+        };
+        let written = overlay.is_written(pos / OVERLAY_BLOCK);
+        let mut block = pos / OVERLAY_BLOCK + 1;
+        while block * OVERLAY_BLOCK < end && overlay.is_written(block) == written {
+            block += 1;
+        }
+        (written, (block * OVERLAY_BLOCK).min(end))
+    }
+
+    async fn read(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; len];
+        let end = offset + len as u64;
+        let mut pos = offset;
+        while pos < end {
+            let (written, run_end) = self.layer_run(pos, end);
+            let part = &mut buf[(pos - offset) as usize..(run_end - offset) as usize];
+            match (written, &self.overlay) {
+                (true, Some(overlay)) => {
+                    part.copy_from_slice(&overlay.read(pos, part.len()).await?);
+                }
+                _ => {
+                    if self.chunks.read_at(part, pos).await? < part.len() {
+                        return Err(ErrorKind::UnexpectedEof.into());
+                    }
+                }
+            }
+            pos = run_end;
+        }
+        Ok(buf)
+    }
+
+    async fn write(&self, overlay: &Overlay, offset: u64, data: &[u8]) -> io::Result<()> {
+        // partial blocks are filled with their current contents, one write at a time so
+        // two writes into the same block don't lose each other's data
+        let _guard = overlay.write_lock.lock().await;
+        let end = offset + data.len() as u64;
+        let start = offset / OVERLAY_BLOCK * OVERLAY_BLOCK;
+        let aligned_end = (end.div_ceil(OVERLAY_BLOCK) * OVERLAY_BLOCK).min(self.size());
+        let mut buf = vec![0; (aligned_end - start) as usize];
+        if start < offset {
+            let len = OVERLAY_BLOCK.min(aligned_end - start) as usize;
+            buf[..len].copy_from_slice(&self.read(start, len).await?);
+        }
+        // unless the head block already covered it
+        let tail = end / OVERLAY_BLOCK * OVERLAY_BLOCK;
+        if end < aligned_end && tail >= offset {
+            let len = (aligned_end - tail) as usize;
+            let at = (tail - start) as usize;
+            buf[at..at + len].copy_from_slice(&self.read(tail, len).await?);
+        }
+        let at = (offset - start) as usize;
+        buf[at..at + data.len()].copy_from_slice(data);
+        overlay.write(start, buf).await?;
+        overlay.mark(start / OVERLAY_BLOCK..aligned_end.div_ceil(OVERLAY_BLOCK));
+        Ok(())
+    }
+
+    // Extents of `len` bytes at `offset` as (length, state flags)
+    fn block_status(&self, offset: u64, len: u32, req_one: bool) -> Vec<(u32, u32)> {
+        let end = offset + u64::from(len);
+        let mut extents = Vec::<(u32, u32)>::new();
+        let mut pos = offset;
+        while pos < end {
+            let idx = self.chunks.chunk_at(pos);
+            let stop = self.chunks.chunk_range(idx).end.min(end);
+            let written = self
+                .overlay
+                .as_ref()
+                .is_some_and(|x| x.any_written(pos..stop));
+            let state = match self.chunks.is_zero(idx) && !written {
+                true => STATE_HOLE | STATE_ZERO,
+                false => 0,
+            };
+            let len = (stop - pos) as u32;
+            match extents.last_mut() {
+                Some(last) if last.1 == state => last.0 += len,
+                _ => extents.push((len, state)),
+            }
+            pos = stop;
+        }
+        if req_one {
+            extents.truncate(1);
+        }
+        extents
+    }
+
+    async fn handle(
+        &self,
+        session: &Session,
+        request: &Request,
+        data: Vec<u8>,
+    ) -> Result<Reply, RequestError> {
+        let Request {
+            flags,
+            kind,
+            offset,
+            len,
+            ..
+        } = *request;
+        let invalid = |message: &str| Err(RequestError(EINVAL, message.to_owned()));
+        let in_bounds = offset
+            .checked_add(len.into())
+            .is_some_and(|end| end <= self.size());
+        let io_error = |e: io::Error| RequestError(EIO, e.to_string());
+        match kind {
+            CMD_READ | CMD_WRITE | CMD_WRITE_ZEROES | CMD_BLOCK_STATUS if !in_bounds => {
+                invalid("request goes past the end of the export")
+            }
+            CMD_READ | CMD_WRITE_ZEROES if len > MAX_REQUEST_LEN => invalid("request too large"),
+            CMD_READ => {
+                let data = self.read(offset, len as usize).await.map_err(io_error)?;
+                Ok(Reply::Data(offset, data))
+            }
+            CMD_WRITE | CMD_WRITE_ZEROES => {
+                let Some(overlay) = &self.overlay else {
+                    return Err(RequestError(EPERM, "export is read-only".to_owned()));
+                };
+                let data = match kind {
+                    CMD_WRITE => data,
+                    _ => vec![0; len as usize],
+                };
+                self.write(overlay, offset, &data).await.map_err(io_error)?;
+                Ok(Reply::Done)
+            }
+            // the overlay is discarded anyway and trimming is only a hint
+            CMD_FLUSH | CMD_TRIM | CMD_CACHE => Ok(Reply::Done),
+            // a reply needs at least one extent
+            CMD_BLOCK_STATUS if len == 0 => invalid("block status of zero bytes"),
+            CMD_BLOCK_STATUS if !session.allocation => {
+                invalid("base:allocation context wasn't negotiated")
+            }
+            CMD_BLOCK_STATUS => {
+                let req_one = flags & CMD_FLAG_REQ_ONE != 0;
+                Ok(Reply::BlockStatus(self.block_status(offset, len, req_one)))
+            }
+            _ => invalid("unknown command"),
+        }
+    }
+}
+
+// Local copy-on-write layer, blocks written by clients are read back from it. Its
+// contents mean nothing without the block map held in memory, so it starts out empty
+// and is removed when the server stops.
+pub struct Overlay {
+    path: PathBuf,
+    file: Arc<File>,
+    // bit per `OVERLAY_BLOCK` bytes that were written
+    written: Mutex<Vec<u64>>,
+    write_lock: tokio::sync::Mutex<()>,
+}
+
+impl Overlay {
+    pub fn create(path: &Path, size: u64) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            // the file is removed again, so never take over an existing one
+            .create_new(true)
+            .open(path)
+            .with_context(|| format!("failed to create overlay {}", path.display()))?;
+        file.set_len(size)?;
+        let blocks = size.div_ceil(OVERLAY_BLOCK);
+        Ok(Self {
+            path: path.to_owned(),
+            file: Arc::new(file),
+            written: Mutex::new(vec![0; blocks.div_ceil(64) as usize]),
+            write_lock: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    fn is_written(&self, block: u64) -> bool {
+        let written = self.written.lock().unwrap();
+        written[(block / 64) as usize] & (1 << (block % 64)) != 0
+    }
+
+    fn any_written(&self, range: Range<u64>) -> bool {
+        let blocks = range.start / OVERLAY_BLOCK..range.end.div_ceil(OVERLAY_BLOCK);
+        blocks.into_iter().any(|block| self.is_written(block))
+    }
+
+    fn mark(&self, blocks: Range<u64>) {
+        let mut written = self.written.lock().unwrap();
+        for block in blocks {
+            written[(block / 64) as usize] |= 1 << (block % 64);
+        }
+    }
+
+    async fn read(&self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let file = self.file.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut buf = vec![0; len];
+            file.read_exact_at(&mut buf, offset)?;
+            Ok(buf)
+        })
+        .await?
+    }
+
+    async fn write(&self, offset: u64, data: Vec<u8>) -> io::Result<()> {
+        let file = self.file.clone();
+        tokio::task::spawn_blocking(move || file.write_all_at(&data, offset)).await?
+    }
+}
+
+impl Drop for Overlay {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("Failed to remove overlay {}: {e}", self.path.display());
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Session {
+    structured: bool,
+    // base:allocation was selected, block status requests are answered
+    allocation: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Request {
+    flags: u16,
+    kind: u16,
+    cookie: u64,
+    offset: u64,
+    len: u32,
+}
+
+// Negotiates and then answers requests until the client disconnects
+pub async fn serve_connection<S>(export: Arc<Export>, mut stream: S) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let Some(session) = negotiate(&mut stream, &export).await? else {
+        return Ok(());
+    };
+    let (mut reader, writer) = tokio::io::split(stream);
+    let replies = Arc::new(Replies {
+        writer: tokio::sync::Mutex::new(writer),
+        structured: session.structured,
+    });
+    let semaphore = Arc::new(Semaphore::new(CONNECTION_REQUESTS));
+    let mut join_set = JoinSet::new();
+    loop {
+        let magic = match reader.read_u32().await {
+            Ok(magic) => magic,
+            // disconnecting without NBD_CMD_DISC
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        };
+        anyhow::ensure!(magic == REQUEST_MAGIC, "invalid request magic {magic:#x}");
+        let request = Request {
+            flags: reader.read_u16().await?,
+            kind: reader.read_u16().await?,
+            cookie: reader.read_u64().await?,
+            offset: reader.read_u64().await?,
+            len: reader.read_u32().await?,
+        };
+        if request.kind == CMD_DISC {
+            break;
+        }
+        // the payload has to be consumed before the next request can be read
+        let mut data = Vec::new();
+        if request.kind == CMD_WRITE {
+            anyhow::ensure!(
+                request.len <= MAX_REQUEST_LEN,
+                "write of {} bytes is too large",
+                request.len
+            );
+            data.resize(request.len as usize, 0);
+            reader.read_exact(&mut data).await?;
+        }
+        let permit = semaphore.clone().acquire_owned().await?;
+        while let Some(result) = join_set.try_join_next() {
+            result??;
+        }
+        let export = export.clone();
+        let replies = replies.clone();
+        join_set.spawn(async move {
+            let _permit = permit;
+            let result = export.handle(&session, &request, data).await;
+            replies.send(request.cookie, result).await
+        });
+    }
+    while let Some(result) = join_set.join_next().await {
+        result??;
+    }
+    Ok(())
+}
+
+// Replies of concurrent requests, each written in one piece
+struct Replies<S> {
+    writer: tokio::sync::Mutex<WriteHalf<S>>,
+    structured: bool,
+}
+
+impl<S: AsyncWrite> Replies<S> {
+    async fn send(&self, cookie: u64, result: Result<Reply, RequestError>) -> io::Result<()> {
+        let mut header = Vec::new();
+        let mut payload: &[u8] = &[];
+        if !self.structured {
+            let errno = result.as_ref().err().map_or(0, |e| e.0);
+            header.extend(SIMPLE_REPLY_MAGIC.to_be_bytes());
+            header.extend(errno.to_be_bytes());
+            header.extend(cookie.to_be_bytes());
+            if let Ok(Reply::Data(_, data)) = &result {
+                payload = data;
+            }
+        } else {
+            let mut body = Vec::new();
+            let kind = match &result {
+                Ok(Reply::Done) => REPLY_TYPE_NONE,
+                Ok(Reply::Data(_, data)) if data.is_empty() => REPLY_TYPE_NONE,
+                Ok(Reply::Data(offset, data)) => {
+                    body.extend(offset.to_be_bytes());
+                    payload = data;
+                    REPLY_TYPE_OFFSET_DATA
+                }
+                Ok(Reply::BlockStatus(extents)) => {
+                    body.extend(ALLOCATION_CONTEXT_ID.to_be_bytes());
+                    for (len, state) in extents {
+                        body.extend(len.to_be_bytes());
+                        body.extend(state.to_be_bytes());
+                    }
+                    REPLY_TYPE_BLOCK_STATUS
+                }
+                Err(RequestError(errno, message)) => {
+                    let message = &message.as_bytes()[..message.len().min(1024)];
+                    body.extend(errno.to_be_bytes());
+                    body.extend((message.len() as u16).to_be_bytes());
+                    body.extend(message);
+                    REPLY_TYPE_ERROR
+                }
+            };
+            let len = (body.len() + payload.len()) as u32;
+            header.extend(STRUCTURED_REPLY_MAGIC.to_be_bytes());
+            header.extend(REPLY_FLAG_DONE.to_be_bytes());
+            header.extend(kind.to_be_bytes());
+            header.extend(cookie.to_be_bytes());
+            header.extend(len.to_be_bytes());
+            header.extend(body);
+        }
+        let mut writer = self.writer.lock().await;
+        writer.write_all(&header).await?;
+        writer.write_all(payload).await?;
+        writer.flush().await
+    }
+}
+
+// Fixed newstyle handshake, `None` if the client aborted
+async fn negotiate<S>(stream: &mut S, export: &Export) -> anyhow::Result<Option<Session>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut hello = Vec::new();
+    hello.extend(NBD_MAGIC.to_be_bytes());
+    hello.extend(IHAVEOPT.to_be_bytes());
+    hello.extend((FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES).to_be_bytes());
+    stream.write_all(&hello).await?;
+    stream.flush().await?;
+    let client_flags = stream.read_u32().await?;
+    anyhow::ensure!(
+        client_flags & CLIENT_FIXED_NEWSTYLE != 0,
+        "client doesn't support fixed newstyle negotiation"
+    );
+    let no_zeroes = client_flags & CLIENT_NO_ZEROES != 0;
+
+    let mut session = Session::default();
+    loop {
+        anyhow::ensure!(stream.read_u64().await? == IHAVEOPT, "invalid option magic");
+        let option = stream.read_u32().await?;
+        let len = stream.read_u32().await?;
+        anyhow::ensure!(len <= MAX_OPTION_LEN, "option of {len} bytes is too large");
+        let mut data = vec![0; len as usize];
+        stream.read_exact(&mut data).await?;
+        let mut replies = Vec::new();
+        match option {
+            OPT_EXPORT_NAME => {
+                // has no way to report errors
+                anyhow::ensure!(
+                    export.matches(&data),
+                    "unknown export {:?}",
+                    String::from_utf8_lossy(&data)
+                );
+                let mut reply = Vec::new();
+                reply.extend(export.size().to_be_bytes());
+                reply.extend(export.transmission_flags().to_be_bytes());
+                if !no_zeroes {
+                    reply.extend([0; 124]);
+                }
+                stream.write_all(&reply).await?;
+                stream.flush().await?;
+                return Ok(Some(session));
+            }
+            OPT_ABORT => {
+                option_reply(stream, option, REP_ACK, &[]).await?;
+                return Ok(None);
+            }
+            OPT_LIST => {
+                let mut server = (export.name.len() as u32).to_be_bytes().to_vec();
+                server.extend(export.name.as_bytes());
+                replies.push((REP_SERVER, server));
+                replies.push((REP_ACK, Vec::new()));
+            }
+            OPT_STRUCTURED_REPLY if len != 0 => replies.push((REP_ERR_INVALID, Vec::new())),
+            OPT_STRUCTURED_REPLY => {
+                session.structured = true;
+                replies.push((REP_ACK, Vec::new()));
+            }
+            OPT_INFO | OPT_GO => match parse_info_request(&data) {
+                None => replies.push((REP_ERR_INVALID, Vec::new())),
+                Some(name) if !export.matches(name) => {
+                    replies.push((REP_ERR_UNKNOWN, Vec::new()));
+                }
+                Some(_) => {
+                    let mut info = INFO_EXPORT.to_be_bytes().to_vec();
+                    info.extend(export.size().to_be_bytes());
+                    info.extend(export.transmission_flags().to_be_bytes());
+                    option_reply(stream, option, REP_INFO, &info).await?;
+                    option_reply(stream, option, REP_ACK, &[]).await?;
+                    if option == OPT_GO {
+                        return Ok(Some(session));
+                    }
+                }
+            },
+            OPT_SET_META_CONTEXT if !session.structured => {
+                replies.push((REP_ERR_INVALID, Vec::new()));
+            }
+            OPT_LIST_META_CONTEXT | OPT_SET_META_CONTEXT => match parse_meta_request(&data) {
+                None => replies.push((REP_ERR_INVALID, Vec::new())),
+                Some((name, _)) if !export.matches(name) => {
+                    replies.push((REP_ERR_UNKNOWN, Vec::new()));
+                }
+                Some((_, queries)) => {
+                    let selected = match option {
+                        // listing without queries or with the namespace returns everything
+                        OPT_LIST_META_CONTEXT => {
+                            queries.is_empty()
+                                || queries
+                                    .iter()
+                                    .any(|x| *x == b"base:" || *x == ALLOCATION_CONTEXT.as_bytes())
+                        }
+                        _ => queries.iter().any(|x| *x == ALLOCATION_CONTEXT.as_bytes()),
+                    };
+                    if selected {
+                        let mut context = ALLOCATION_CONTEXT_ID.to_be_bytes().to_vec();
+                        context.extend(ALLOCATION_CONTEXT.as_bytes());
+                        replies.push((REP_META_CONTEXT, context));
+                    }
+                    if option == OPT_SET_META_CONTEXT {
+                        session.allocation = selected;
+                    }
+                    replies.push((REP_ACK, Vec::new()));
+                }
+            },
+            _ => replies.push((REP_ERR_UNSUP, Vec::new())),
+        }
+        for (kind, data) in replies {
+            option_reply(stream, option, kind, &data).await?;
+        }
+    }
+}
+
+async fn option_reply<S>(stream: &mut S, option: u32, kind: u32, data: &[u8]) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut reply = Vec::new();
+    reply.extend(OPTION_REPLY_MAGIC.to_be_bytes());
+    reply.extend(option.to_be_bytes());
+    reply.extend(kind.to_be_bytes());
+    reply.extend((data.len() as u32).to_be_bytes());
+    reply.extend(data);
+    stream.write_all(&reply).await?;
+    stream.flush().await
+}
+
+// Reads big-endian fields of option data
+struct OptionData<'a>(&'a [u8]);
+
+impl<'a> OptionData<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    // string prefixed by its u32 length
+    fn string(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()?;
+        self.bytes(len as usize)
+    }
+}
+
+// Export name of NBD_OPT_INFO and NBD_OPT_GO, the requested info types are ignored
+fn parse_info_request(data: &[u8]) -> Option<&[u8]> {
+    let mut data = OptionData(data);
+    let name = data.string()?;
+    let count = data.u16()?;
+    data.bytes(2 * count as usize)?;
+    data.0.is_empty().then_some(name)
+}
+
+// Export name and queries of the meta context options
+fn parse_meta_request(data: &[u8]) -> Option<(&[u8], Vec<&[u8]>)> {
+    let mut data = OptionData(data);
+    let name = data.string()?;
+    let count = data.u32()?;
+    let queries = (0..count)
+        .map(|_| data.string())
+        .collect::<Option<Vec<_>>>()?;
+    data.0.is_empty().then_some((name, queries))
+}
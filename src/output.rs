@@ -0,0 +1,152 @@
+use crate::sparse::device_size;
+use anyhow::Context;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::{FileExt, FileTypeExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+// O_DIRECT needs offsets, lengths and buffers aligned to the logical block size, 4 KiB
+// covers both 512 byte and 4K sector devices.
+pub const DIRECT_ALIGN: usize = 4096;
+
+// Where a restore writes to, either a regular file or a block device
+pub struct RestoreOutput {
+    file: File,
+    // same target opened with O_DIRECT, used for aligned writes
+    direct: Option<File>,
+    block_device: bool,
+}
+
+impl RestoreOutput {
+    // Opens the target for a restore of `size` bytes. Existing non-empty targets are only
+    // overwritten with `force`, or when updating them `in_place`. Block devices are opened
+    // exclusively, which fails while they are mounted or in use, and must be large enough.
+    pub fn open(
+        path: &Path,
+        size: u64,
+        in_place: bool,
+        force: bool,
+        direct: bool,
+    ) -> anyhow::Result<Self> {
+        let metadata = match std::fs::metadata(path) {
+            Ok(metadata) => Some(metadata),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).with_context(|| format!("stat {}", path.display())),
+        };
+        let block_device = metadata
+            .as_ref()
+            .is_some_and(|m| m.file_type().is_block_device());
+
+        let open = |flags: libc::c_int| {
+            let mut options = OpenOptions::new();
+            options.read(true).write(true).custom_flags(flags);
+            if !block_device {
+                options.create(true);
+            }
+            options.open(path).map_err(|e| {
+                let e = if block_device && e.raw_os_error() == Some(libc::EBUSY) {
+                    anyhow::anyhow!("{} is mounted or in use", path.display())
+                } else {
+                    anyhow::Error::new(e)
+                };
+                e.context(format!("open {}", path.display()))
+            })
+        };
+        // O_EXCL without O_CREAT claims a block device exclusively
+        let file = open(if block_device { libc::O_EXCL } else { 0 })?;
+
+        let existing_len = device_size(&file)?;
+        if existing_len > 0 && !in_place && !force {
+            anyhow::bail!(
+                "refusing to overwrite non-empty {}, pass --force",
+                path.display()
+            );
+        }
+        if block_device {
+            anyhow::ensure!(
+                existing_len >= size,
+                "{} has {existing_len} bytes, the version needs {size}",
+                path.display()
+            );
+        } else if !in_place {
+            file.set_len(0)?;
+        }
+
+        // the buffered descriptor holds the exclusive claim, a second one would conflict
+        let direct = if direct {
+            Some(open(libc::O_DIRECT)?)
+        } else {
+            None
+        };
+        Ok(Self {
+            file,
+            direct,
+            block_device,
+        })
+    }
+
+    pub fn is_block_device(&self) -> bool {
+        self.block_device
+    }
+
+    pub fn current_len(&self) -> std::io::Result<u64> {
+        device_size(&self.file)
+    }
+
+    pub fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+        self.file.read_exact_at(buf, offset)
+    }
+
+    pub fn write_all_at(&self, data: &[u8], offset: u64) -> std::io::Result<()> {
+        match &self.direct {
+            Some(direct)
+                if offset.is_multiple_of(DIRECT_ALIGN as u64)
+                    && data.len().is_multiple_of(DIRECT_ALIGN) =>
+            {
+                let mut buf = vec![0u8; data.len() + DIRECT_ALIGN];
+                let start = buf.as_ptr().align_offset(DIRECT_ALIGN);
+                let aligned = &mut buf[start..start + data.len()];
+                aligned.copy_from_slice(data);
+                direct.write_all_at(aligned, offset)
+            }
+            // unaligned chunks, like a short last one, go through the page cache
+            _ => self.file.write_all_at(data, offset),
+        }
+    }
+
+    // Deallocates `len` bytes at `offset` so they read as zeros, `false` if the target
+    // can't punch holes there
+    pub fn punch_hole(&self, offset: u64, len: u64) -> std::io::Result<bool> {
+        let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+        // SAFETY: plain syscall on an open descriptor
+        let ret = unsafe {
+            libc::fallocate(
+                self.file.as_raw_fd(),
+                mode,
+                offset as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        if ret == 0 {
+            return Ok(true);
+        }
+        let e = std::io::Error::last_os_error();
+        match e.raw_os_error() {
+            // unsupported, or unaligned on a block device
+            Some(libc::EOPNOTSUPP | libc::EINVAL) => Ok(false),
+            _ => Err(e),
+        }
+    }
+
+    // Trims a regular file to `size` and flushes everything to stable storage. Block
+    // devices keep whatever follows the restored data.
+    pub fn finish(&self, size: u64) -> std::io::Result<()> {
+        if !self.block_device {
+            self.file.set_len(size)?;
+        }
+        if let Some(direct) = &self.direct {
+            direct.sync_all()?;
+        }
+        self.file.sync_all()
+    }
+}
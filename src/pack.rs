@@ -0,0 +1,80 @@
+use bincode::{Decode, Encode};
+
+pub const DEFAULT_PACK_SIZE: usize = 64 * 1024 * 1024;
+
+// Stored next to every pack, a pack without one is an unfinished upload
+#[derive(Encode, Decode, Clone, Debug, Default)]
+pub struct PackIndex {
+    pub entries: Vec<PackEntry>,
+}
+
+// Location of one encoded chunk within the pack
+#[derive(Encode, Decode, Clone, Copy, Debug)]
+pub struct PackEntry {
+    pub hash: [u8; 32],
+    pub offset: u64,
+    pub len: u32,
+}
+
+impl PackEntry {
+    pub fn range(&self) -> std::ops::Range<usize> {
+        self.offset as usize..self.offset as usize + self.len as usize
+    }
+}
+
+pub struct Pack {
+    pub data: Vec<u8>,
+    pub index: PackIndex,
+}
+
+impl Pack {
+    // Packs are named by their content, so retried uploads don't leave duplicates
+    pub fn id(&self) -> String {
+        blake3::hash(&self.data).to_hex().to_string()
+    }
+}
+
+// Appends encoded chunks until the pack reaches its target size
+pub struct PackBuilder {
+    target_size: usize,
+    data: Vec<u8>,
+    entries: Vec<PackEntry>,
+}
+
+impl PackBuilder {
+    pub fn new(target_size: usize) -> Self {
+        Self {
+            target_size,
+            data: Vec::new(),
+            entries: Vec::new(),
+        }
+    }
+
+    // Returns the pack once it is full
+    pub fn add(&mut self, hash: blake3::Hash, encoded: &[u8]) -> Option<Pack> {
+        self.entries.push(PackEntry {
+            hash: hash.into(),
+            offset: self.data.len() as u64,
+            len: encoded.len() as u32,
+        });
+        self.data.extend_from_slice(encoded);
+        if self.data.len() >= self.target_size {
+            self.finish()
+        } else {
+            None
+        }
+    }
+
+    // Takes whatever was added so far
+    pub fn finish(&mut self) -> Option<Pack> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        Some(Pack {
+            data: std::mem::take(&mut self.data),
+            index: PackIndex {
+                entries: std::mem::take(&mut self.entries),
+            },
+        })
+    }
+}
@@ -0,0 +1,314 @@
+use crate::blob::{Blob, ZERO_CHUNK};
+use crate::fetch_chunk;
+use crate::storage::Storage;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use std::collections::VecDeque;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, ReadBuf};
+use tokio::runtime::Handle;
+
+// chunks kept in memory, 16 MiB with the default chunk size
+pub const READER_CACHE_CHUNKS: usize = 32;
+// chunks fetched in the background once reads are sequential
+pub const READ_AHEAD_CHUNKS: usize = 4;
+
+type ChunkFuture = Shared<BoxFuture<'static, Result<Arc<[u8]>, Arc<anyhow::Error>>>>;
+
+// Chunks of one version behind a cache shared by all clones, so concurrent readers fetch
+// each chunk once. Chunks may be deleted by gc while in use, unless the caller holds a
+// shared lock.
+#[derive(Clone)]
+pub struct VersionChunks {
+    storage: Storage,
+    hashes: Arc<[blake3::Hash]>,
+    // start of every chunk, followed by the size of the version
+    offsets: Arc<[u64]>,
+    cache: Arc<Mutex<ChunkLru>>,
+    read_ahead: usize,
+}
+
+impl VersionChunks {
+    pub fn new(storage: Storage, blob: &Blob) -> Self {
+        let mut offsets = vec![0];
+        let mut hashes = Vec::with_capacity(blob.chunk_count());
+        for (hash, len) in blob.chunks() {
+            offsets.push(offsets.last().unwrap() + len);
+            hashes.push(hash);
+        }
+        Self {
+            storage,
+            hashes: hashes.into(),
+            offsets: offsets.into(),
+            cache: Arc::new(Mutex::new(ChunkLru::new(READER_CACHE_CHUNKS))),
+            read_ahead: READ_AHEAD_CHUNKS,
+        }
+    }
+
+    // Starts a separate cache
+    pub fn with_cache_size(self, chunks: usize) -> Self {
+        Self {
+            cache: Arc::new(Mutex::new(ChunkLru::new(chunks))),
+            ..self
+        }
+    }
+
+    pub fn with_read_ahead(self, chunks: usize) -> Self {
+        Self {
+            read_ahead: chunks,
+            ..self
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        *self.offsets.last().unwrap()
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.hashes.len()
+    }
+
+    // Index of the chunk containing `pos`, which must be before the end
+    pub fn chunk_at(&self, pos: u64) -> usize {
+        self.offsets.partition_point(|&x| x <= pos) - 1
+    }
+
+    // Byte range of chunk `idx`
+    pub fn chunk_range(&self, idx: usize) -> Range<u64> {
+        self.offsets[idx]..self.offsets[idx + 1]
+    }
+
+    pub fn is_zero(&self, idx: usize) -> bool {
+        self.hashes[idx] == ZERO_CHUNK
+    }
+
+    // Fills `buf` from `offset` as far as the version goes, returns the bytes read
+    pub async fn read_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<usize> {
+        let mut total = 0;
+        while !buf.is_empty() && offset < self.size() {
+            let idx = self.chunk_at(offset);
+            let data = self.chunk(idx).await?;
+            let start = (offset - self.offsets[idx]) as usize;
+            let len = buf.len().min(data.len() - start);
+            buf[..len].copy_from_slice(&data[start..start + len]);
+            buf = &mut buf[len..];
+            offset += len as u64;
+            total += len;
+        }
+        Ok(total)
+    }
+
+    // Data of chunk `idx`, a failed fetch is retried by the next call
+    pub async fn chunk(&self, idx: usize) -> io::Result<Arc<[u8]>> {
+        let result = self.start_fetch(idx).await;
+        result.map_err(|e| {
+            self.cache.lock().unwrap().remove(idx);
+            io::Error::other(format!("{e:#}"))
+        })
+    }
+
+    // Cached or newly started fetch of chunk `idx`, reading the chunk after the previous
+    // one also starts fetching the following chunks
+    fn start_fetch(&self, idx: usize) -> ChunkFuture {
+        let mut cache = self.cache.lock().unwrap();
+        let sequential = cache.last_read.is_some_and(|x| x + 1 == idx);
+        cache.last_read = Some(idx);
+        if sequential {
+            let end = (idx + 1 + self.read_ahead).min(self.hashes.len());
+            for ahead in idx + 1..end {
+                if cache.get(ahead).is_none() {
+                    let fetch = self.fetch(ahead);
+                    tokio::spawn(fetch.clone());
+                    cache.insert(ahead, fetch);
+                }
+            }
+        }
+        if let Some(fetch) = cache.get(idx) {
+            return fetch;
+        }
+        let fetch = self.fetch(idx);
+        cache.insert(idx, fetch.clone());
+        fetch
+    }
+
+    fn fetch(&self, idx: usize) -> ChunkFuture {
+        let storage = self.storage.clone();
+        let hash = self.hashes[idx];
+        let range = self.chunk_range(idx);
+        async move {
+            let data = fetch_chunk(&storage, &hash, range.end - range.start).await?;
+            Ok(data.into())
+        }
+        .map(|result| result.map_err(Arc::new))
+        .boxed()
+        .shared()
+    }
+}
+
+// Random access to one version, only the chunks that are read get fetched
+pub struct VersionReader {
+    chunks: VersionChunks,
+    pos: u64,
+    // read the pending `poll_read` waits for
+    pending: Option<BoxFuture<'static, io::Result<Arc<[u8]>>>>,
+}
+
+impl VersionReader {
+    pub fn new(storage: Storage, blob: &Blob) -> Self {
+        Self::from_chunks(VersionChunks::new(storage, blob))
+    }
+
+    pub fn from_chunks(chunks: VersionChunks) -> Self {
+        Self {
+            chunks,
+            pos: 0,
+            pending: None,
+        }
+    }
+
+    pub fn with_cache_size(self, chunks: usize) -> Self {
+        Self::from_chunks(self.chunks.with_cache_size(chunks))
+    }
+
+    pub fn with_read_ahead(self, chunks: usize) -> Self {
+        Self::from_chunks(self.chunks.with_read_ahead(chunks))
+    }
+
+    pub fn chunks(&self) -> &VersionChunks {
+        &self.chunks
+    }
+
+    pub fn size(&self) -> u64 {
+        self.chunks.size()
+    }
+
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    // `Read + Seek` adapter that blocks on `handle`, must not be used on a runtime thread
+    pub fn into_blocking(self, handle: Handle) -> BlockingVersionReader {
+        BlockingVersionReader {
+            reader: self,
+            handle,
+        }
+    }
+
+    // Fills `buf` from `offset` as far as the version goes, returns the bytes read
+    pub async fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let len = self.chunks.read_at(buf, offset).await?;
+        self.pos = offset + len as u64;
+        Ok(len)
+    }
+}
+
+impl AsyncRead for VersionReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.pos >= this.size() || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let idx = this.chunks.chunk_at(this.pos);
+        let pending = this.pending.get_or_insert_with(|| {
+            let chunks = this.chunks.clone();
+            async move { chunks.chunk(idx).await }.boxed()
+        });
+        let result = ready!(pending.poll_unpin(cx));
+        this.pending = None;
+        let data = result?;
+        let start = (this.pos - this.chunks.chunk_range(idx).start) as usize;
+        let len = buf.remaining().min(data.len() - start);
+        buf.put_slice(&data[start..start + len]);
+        this.pos += len as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for VersionReader {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        // a read in progress is for the old position
+        this.pending = None;
+        let pos = match position {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => this.size().checked_add_signed(delta),
+            SeekFrom::Current(delta) => this.pos.checked_add_signed(delta),
+        };
+        this.pos = pos
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.pos))
+    }
+}
+
+// Blocking `Read + Seek` over a `VersionReader`, for code that isn't async
+pub struct BlockingVersionReader {
+    reader: VersionReader,
+    handle: Handle,
+}
+
+impl BlockingVersionReader {
+    pub fn into_inner(self) -> VersionReader {
+        self.reader
+    }
+}
+
+impl Read for BlockingVersionReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.handle.block_on(self.reader.read(buf))
+    }
+}
+
+impl Seek for BlockingVersionReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.handle.block_on(self.reader.seek(pos))
+    }
+}
+
+// Least recently used chunks, few enough that a linear scan is fine
+struct ChunkLru {
+    capacity: usize,
+    // most recently used last
+    entries: VecDeque<(usize, ChunkFuture)>,
+    last_read: Option<usize>,
+}
+
+impl ChunkLru {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: VecDeque::new(),
+            last_read: None,
+        }
+    }
+
+    fn get(&mut self, idx: usize) -> Option<ChunkFuture> {
+        let pos = self.entries.iter().position(|(x, _)| *x == idx)?;
+        let entry = self.entries.remove(pos)?;
+        let fetch = entry.1.clone();
+        self.entries.push_back(entry);
+        Some(fetch)
+    }
+
+    fn insert(&mut self, idx: usize, fetch: ChunkFuture) {
+        self.remove(idx);
+        self.entries.push_back((idx, fetch));
+        while self.entries.len() > self.capacity {
+            self.entries.pop_front();
+        }
+    }
+
+    fn remove(&mut self, idx: usize) {
+        self.entries.retain(|(x, _)| *x != idx);
+    }
+}
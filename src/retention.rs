@@ -0,0 +1,100 @@
+use crate::blob::Document;
+use chrono::{DateTime, Datelike, Utc};
+
+// Decides which old versions of a document survive a prune.
+// A version is kept if any rule selects it, the current version is always kept.
+#[derive(Clone, Debug, Default)]
+pub struct RetentionPolicy {
+    // keep the n newest versions
+    pub keep_last: usize,
+    // keep the newest version of each of the last n hours/days/... that have versions
+    pub keep_hourly: usize,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+    pub keep_monthly: usize,
+    pub keep_yearly: usize,
+    // after applying the rules, drop the oldest versions until history retains at most this
+    pub max_retained_size: Option<u64>,
+}
+
+type BucketKey = fn(&DateTime<Utc>) -> i64;
+
+impl RetentionPolicy {
+    fn has_keep_rules(&self) -> bool {
+        self.buckets().iter().any(|(count, _)| *count > 0) || self.keep_last > 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.has_keep_rules() && self.max_retained_size.is_none()
+    }
+
+    fn buckets(&self) -> [(usize, BucketKey); 5] {
+        [
+            (self.keep_hourly, |t| t.timestamp().div_euclid(3600)),
+            (self.keep_daily, |t| t.num_days_from_ce().into()),
+            (self.keep_weekly, |t| {
+                let week = t.iso_week();
+                i64::from(week.year()) * 100 + i64::from(week.week())
+            }),
+            (self.keep_monthly, |t| {
+                i64::from(t.year()) * 12 + i64::from(t.month0())
+            }),
+            (self.keep_yearly, |t| t.year().into()),
+        ]
+    }
+
+    // Takes version timestamps oldest first and returns which of them to keep
+    pub fn select(&self, timestamps: &[DateTime<Utc>]) -> Vec<bool> {
+        // without keep rules only the size budget prunes
+        let mut keep = vec![!self.has_keep_rules(); timestamps.len()];
+        if let Some(current) = keep.last_mut() {
+            *current = true;
+        }
+        for k in keep.iter_mut().rev().take(self.keep_last) {
+            *k = true;
+        }
+        for (count, key) in self.buckets() {
+            let mut last_bucket = None;
+            let mut used = 0;
+            for (n, time) in timestamps.iter().enumerate().rev() {
+                if used == count {
+                    break;
+                }
+                let bucket = key(time);
+                if last_bucket != Some(bucket) {
+                    last_bucket = Some(bucket);
+                    keep[n] = true;
+                    used += 1;
+                }
+            }
+        }
+        keep
+    }
+
+    // Prune `doc` in place, returns the number of dropped versions
+    pub fn apply(&self, doc: &mut Document) -> usize {
+        let before = doc.version_count();
+        let timestamps = doc.version_timestamps().collect::<Vec<_>>();
+        let keep = self.select(&timestamps);
+        if keep.iter().any(|k| !k) {
+            doc.retain_versions(|n| keep[n]);
+        }
+
+        if let Some(budget) = self.max_retained_size {
+            // diffs point to newer versions, so dropping the oldest ones never changes the rest
+            let mut total = 0;
+            let within_budget = doc
+                .versions()
+                .take_while(|version| {
+                    total += version.retained_size();
+                    total <= budget
+                })
+                .count();
+            let drop = doc.version_count() - 1 - within_budget;
+            if drop > 0 {
+                doc.retain_versions(|n| n >= drop);
+            }
+        }
+        before - doc.version_count()
+    }
+}
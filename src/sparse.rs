@@ -0,0 +1,133 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+// A source file read extent by extent, holes are zero-filled instead of read
+pub struct SparseFile {
+    file: File,
+    // length when opened, reads stop there
+    size: u64,
+    pos: u64,
+    // last lookup: [from, data_start) is a hole, [data_start, data_end) holds data
+    extent: Option<(u64, u64, u64)>,
+}
+
+impl SparseFile {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let size = device_size(&file)?;
+        Ok(Self {
+            file,
+            size,
+            pos: 0,
+            extent: None,
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // Next data extent as of `offset`, the start is before `offset` when it is in data
+    fn extent(&mut self, offset: u64) -> io::Result<(u64, u64)> {
+        if let Some((from, start, end)) = self.extent {
+            if from <= offset && offset < end {
+                return Ok((start, end));
+            }
+        }
+        let extent = match lseek(&self.file, offset, libc::SEEK_DATA) {
+            Ok(start) => (start, lseek(&self.file, start, libc::SEEK_HOLE)?),
+            // only a hole follows
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => (self.size, self.size),
+            // no hole support, like block devices, so everything is data
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => (offset, u64::MAX),
+            Err(e) => return Err(e),
+        };
+        self.extent = Some((offset, extent.0, extent.1));
+        Ok(extent)
+    }
+
+    // Whether `len` bytes at `offset` are entirely a hole
+    pub fn is_hole(&mut self, offset: u64, len: u64) -> io::Result<bool> {
+        let (start, _) = self.extent(offset)?;
+        Ok(start >= offset + len)
+    }
+
+    // Like `FileExt::read_at`, a read stops at the next hole or data boundary
+    pub fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let remaining = self.size.saturating_sub(offset);
+        let len = (buf.len() as u64).min(remaining) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+        let (start, end) = self.extent(offset)?;
+        if offset < start {
+            let len = len.min((start - offset) as usize);
+            buf[..len].fill(0);
+            return Ok(len);
+        }
+        let len = (len as u64).min(end - offset) as usize;
+        self.file.read_at(&mut buf[..len], offset)
+    }
+
+    pub fn read_exact_at(&mut self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset)? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // `len` bytes at `offset`, `None` without reading anything if they are a hole
+    pub fn read_chunk_at(&mut self, offset: u64, len: usize) -> io::Result<Option<Vec<u8>>> {
+        if self.is_hole(offset, len as u64)? {
+            return Ok(None);
+        }
+        let mut data = vec![0; len];
+        self.read_exact_at(&mut data, offset)?;
+        Ok(Some(data))
+    }
+}
+
+impl Read for SparseFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.read_at(buf, self.pos)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for SparseFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = pos.ok_or(io::ErrorKind::InvalidInput)?;
+        Ok(self.pos)
+    }
+}
+
+// Length of a file or block device, metadata reports 0 for block devices but seeking works
+pub fn device_size(mut file: &File) -> io::Result<u64> {
+    file.seek(SeekFrom::End(0))
+}
+
+fn lseek(file: &File, offset: u64, whence: libc::c_int) -> io::Result<u64> {
+    let offset = libc::off_t::try_from(offset).map_err(|_| io::ErrorKind::InvalidInput)?;
+    // SAFETY: plain syscall on an open descriptor, reads go through `pread` which
+    // doesn't use the file position
+    let ret = unsafe { libc::lseek(file.as_raw_fd(), offset, whence) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as u64)
+}
@@ -1,26 +1,331 @@
-use crate::blob::Document;
-
+use crate::{
+    blob::Document,
+    checkpoint::Checkpoint,
+    chunker::Chunker,
+    codec::{self, Compression},
+    crypto::{self, EncryptionConfig, RepoKey},
+    lock::LockInfo,
+    pack::{Pack, PackEntry, PackIndex},
+};
+use anyhow::Context;
 use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use bincode::{Decode, Encode};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
 use futures::{stream, StreamExt};
-use object_store::{path::Path, ObjectStore};
-use std::sync::Arc;
+use object_store::{path::Path, ObjectStore, PutMode, UpdateVersion};
+use rand::RngCore;
+use std::{
+    collections::HashMap,
+    fmt,
+    path::PathBuf,
+    sync::{atomic::AtomicBool, Arc, Mutex},
+};
+use tracing::{info, warn};
 
 #[derive(Clone)]
 pub struct Storage {
     store: Arc<dyn ObjectStore>,
+    set_name: String,
     root_key: Path,
+    // applied to chunks written through this handle, reading handles any codec
+    compression: Compression,
+    // set once unlocked, chunks and root documents are then encrypted
+    key: Option<RepoKey>,
+    // where `ChunkCache` keeps the known chunks between runs, `None` disables it
+    cache_dir: Option<PathBuf>,
+    // chunks uploaded by backups go into packs of this size instead of their own objects
+    pack_size: Option<usize>,
+    // shared by all clones, pack indexes never change once written
+    packs: Arc<Mutex<PackCache>>,
+}
+
+#[derive(Default)]
+struct PackCache {
+    indexes: HashMap<String, Arc<PackIndex>>,
+    chunks: HashMap<[u8; 32], (String, PackEntry)>,
 }
 
-const ROOT_KEY: &str = "Root";
+impl PackCache {
+    fn insert(&mut self, id: &str, index: Arc<PackIndex>) {
+        for entry in &index.entries {
+            self.chunks.insert(entry.hash, (id.to_owned(), *entry));
+        }
+        self.indexes.insert(id.to_owned(), index);
+    }
+
+    fn remove(&mut self, id: &str) {
+        if let Some(index) = self.indexes.remove(id) {
+            for entry in &index.entries {
+                if self.chunks.get(&entry.hash).is_some_and(|(x, _)| x == id) {
+                    self.chunks.remove(&entry.hash);
+                }
+            }
+        }
+    }
+}
+
+// Version of the root document as seen when reading it, `None` if it didn't exist
+#[derive(Clone, Debug, Default)]
+pub struct RootVersion(Option<UpdateVersion>);
+
+// Version of a lock object as last written by its holder
+#[derive(Clone, Debug)]
+pub struct LockVersion(UpdateVersion);
+
+// Returned by `put_root_metadata` when the root changed since it was read
+#[derive(Debug)]
+pub struct RootConflict;
+
+impl fmt::Display for RootConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("root document was changed concurrently")
+    }
+}
+
+impl std::error::Error for RootConflict {}
+
+pub struct ChunkMeta {
+    pub hash: blake3::Hash,
+    // stored object size, including the chunk header and after compression
+    pub size: u64,
+    // of the pack for packed chunks
+    pub last_modified: DateTime<Utc>,
+    // id of the pack holding the chunk, `None` for chunks stored as their own object
+    pub pack: Option<String>,
+    // position within the pack, 0 for chunks stored as their own object
+    pub offset: u64,
+}
+
+pub struct PackMeta {
+    pub id: String,
+    pub size: u64,
+    pub last_modified: DateTime<Utc>,
+}
+
+// Chunks found unreferenced by a gc run, only these may be deleted by a later run
+#[derive(Encode, Decode, Clone, Debug, Default)]
+pub struct GcMark {
+    pub timestamp: i64,
+    pub candidates: Vec<[u8; 32]>,
+}
+
+impl GcMark {
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        DateTime::from_timestamp(self.timestamp, 0).unwrap()
+    }
+}
+
+// Identifies the repository for local caches, `generation` is bumped whenever chunks
+// are deleted so cached chunk lists can't claim deleted chunks exist
+#[derive(Encode, Decode, Clone, Copy, Debug, PartialEq)]
+pub struct RepoGeneration {
+    pub repo_id: [u8; 16],
+    pub generation: u64,
+}
+
+// Repository wide settings, written once by `init`
+#[derive(Encode, Decode, Clone, Debug, Default, PartialEq)]
+pub struct RepoConfig {
+    // chunking of newly created sets
+    pub chunker: Chunker,
+    pub encryption: Option<EncryptionConfig>,
+}
+
+pub const DEFAULT_SET: &str = "default";
+// every backup set has its own root document under this prefix, chunks are shared
+const SET_KEY_PREFIX: &str = "Set";
+// root document of repositories from before backup sets, moved to the default set
+const LEGACY_ROOT_KEY: &str = "Root";
 const CHUNK_KEY_PREFIX: char = 'C';
+const LOCK_KEY_PREFIX: &str = "Lock";
+const GC_MARK_KEY: &str = "GcMark";
+const CONFIG_KEY: &str = "Config";
+const GENERATION_KEY: &str = "Generation";
+const CHECKPOINT_KEY_PREFIX: &str = "Checkpoint";
+const PACK_KEY_PREFIX: &str = "Pack";
+const PACK_INDEX_KEY_PREFIX: &str = "PackIndex";
 impl Storage {
     pub fn new(store: Arc<dyn ObjectStore>) -> anyhow::Result<Self> {
-        Ok(Self {
+        Self {
             store,
-            root_key: Path::from(ROOT_KEY),
+            set_name: String::new(),
+            root_key: Path::default(),
+            compression: Compression::None,
+            key: None,
+            pack_size: None,
+            packs: Default::default(),
+            cache_dir: None,
+        }
+        .with_set(DEFAULT_SET)
+    }
+
+    pub fn with_pack_size(self, pack_size: usize) -> Self {
+        Self {
+            pack_size: Some(pack_size),
+            ..self
+        }
+    }
+
+    pub fn pack_size(&self) -> Option<usize> {
+        self.pack_size
+    }
+
+    pub fn with_cache_dir(self, cache_dir: PathBuf) -> Self {
+        Self {
+            cache_dir: Some(cache_dir),
+            ..self
+        }
+    }
+
+    pub fn cache_dir(&self) -> Option<&std::path::Path> {
+        self.cache_dir.as_deref()
+    }
+
+    pub fn with_compression(self, compression: Compression) -> Self {
+        Self {
+            compression,
+            ..self
+        }
+    }
+
+    pub fn with_key(self, key: RepoKey) -> Self {
+        Self {
+            key: Some(key),
+            ..self
+        }
+    }
+
+    // Derives the key from `secret` if the repository config asks for encryption
+    pub async fn unlock(self, secret: Option<&[u8]>) -> anyhow::Result<Self> {
+        let encryption = self.get_config().await?.and_then(|x| x.encryption);
+        match (encryption, secret) {
+            (None, None) => Ok(self),
+            (None, Some(_)) => anyhow::bail!("repository is not encrypted, but a key was given"),
+            (Some(_), None) => anyhow::bail!("repository is encrypted, a key is required"),
+            (Some(encryption), Some(secret)) => {
+                let secret = secret.to_owned();
+                let key = tokio::task::spawn_blocking(move || encryption.unlock(&secret)).await??;
+                Ok(self.with_key(key))
+            }
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.key.is_some()
+    }
+
+    // Chunks are named by their plain hash, or a keyed one in encrypted repositories
+    pub fn chunk_id(&self, data: &[u8]) -> blake3::Hash {
+        match &self.key {
+            Some(key) => key.chunk_id(data),
+            None => blake3::hash(data),
+        }
+    }
+
+    // Same as `chunk_id`, but can be moved to other threads
+    pub fn chunk_hasher(&self) -> impl Fn(&[u8]) -> blake3::Hash + Clone + Send + 'static {
+        let key = self.key.clone();
+        move |data| match &key {
+            Some(key) => key.chunk_id(data),
+            None => blake3::hash(data),
+        }
+    }
+
+    // Largest difference between the stored and plain size of a chunk
+    pub fn chunk_overhead(&self) -> u64 {
+        let encryption = if self.is_encrypted() {
+            crypto::OVERHEAD
+        } else {
+            0
+        };
+        (codec::HEADER_LEN + encryption) as u64
+    }
+
+    // Same repository, but operating on the root document of another backup set
+    pub fn with_set(&self, name: &str) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+                && !name.starts_with('.'),
+            "invalid backup set name {name:?}, use letters, digits, '-', '_' and '.'"
+        );
+        Ok(Self {
+            set_name: name.to_owned(),
+            root_key: Path::from_iter([SET_KEY_PREFIX, name]),
+            ..self.clone()
         })
     }
 
+    pub fn set_name(&self) -> &str {
+        &self.set_name
+    }
+
+    pub async fn list_sets(&self) -> anyhow::Result<Vec<String>> {
+        let prefix = Path::from(SET_KEY_PREFIX);
+        let mut sets = Vec::new();
+        let mut list = self.store.list(Some(&prefix));
+        while let Some(meta) = list.next().await {
+            if let Some(name) = meta?.location.filename() {
+                sets.push(name.to_owned());
+            }
+        }
+        // becomes the default set once it is read
+        if !sets.iter().any(|x| x == DEFAULT_SET) && self.has_legacy_root().await? {
+            sets.push(DEFAULT_SET.to_owned());
+        }
+        sets.sort();
+        Ok(sets)
+    }
+
+    pub async fn has_legacy_root(&self) -> anyhow::Result<bool> {
+        match self.store.head(&Path::from(LEGACY_ROOT_KEY)).await {
+            Ok(_) => Ok(true),
+            Err(object_store::Error::NotFound { .. }) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Moves the legacy root document to the default set unless that already exists,
+    // returns false if there was nothing to move
+    async fn migrate_legacy_root(&self) -> anyhow::Result<bool> {
+        let legacy_key = Path::from(LEGACY_ROOT_KEY);
+        let bytes = match self.store.get(&legacy_key).await {
+            Ok(get_result) => get_result.bytes().await?,
+            Err(object_store::Error::NotFound { .. }) => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        // legacy roots were never encrypted
+        let bytes = self.seal_object(bytes.to_vec(), &self.root_key);
+        match self
+            .store
+            .put_opts(&self.root_key, bytes.clone().into(), PutMode::Create.into())
+            .await
+        {
+            // migrated concurrently
+            Ok(_) | Err(object_store::Error::AlreadyExists { .. }) => {}
+            Err(object_store::Error::NotImplemented) => {
+                if let Err(e) = self
+                    .check_then_put_root(bytes, &RootVersion::default())
+                    .await
+                {
+                    if !e.is::<RootConflict>() {
+                        return Err(e);
+                    }
+                }
+            }
+            Err(e) => return Err(e.into()),
+        }
+        match self.store.delete(&legacy_key).await {
+            Ok(()) | Err(object_store::Error::NotFound { .. }) => {}
+            Err(e) => return Err(e.into()),
+        }
+        info!("Moved the legacy root document to set {DEFAULT_SET}");
+        Ok(true)
+    }
+
     fn chunk_path(key: &[u8]) -> Path {
         let mut s = String::with_capacity(key.len() * 4 / 3 + 2);
         s.push(CHUNK_KEY_PREFIX);
@@ -28,21 +333,97 @@ impl Storage {
         Path::from(s)
     }
 
+    // Compressed and encrypted form of a chunk as it is stored, alone or in a pack
+    pub async fn encode_chunk(
+        &self,
+        hash: &blake3::Hash,
+        data: Vec<u8>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let compression = self.compression;
+        let key = self.key.clone();
+        let hash = *hash.as_bytes();
+        tokio::task::spawn_blocking(move || {
+            let encoded = codec::encode_chunk(&data, compression)?;
+            anyhow::Ok(match key {
+                Some(key) => key.seal(&encoded, &hash),
+                None => encoded,
+            })
+        })
+        .await?
+    }
+
+    async fn decode_chunk(&self, hash: &blake3::Hash, bytes: Bytes) -> anyhow::Result<Vec<u8>> {
+        let key = self.key.clone();
+        let hash = *hash.as_bytes();
+        tokio::task::spawn_blocking(move || match key {
+            Some(key) => codec::decode_chunk(&key.open(&bytes, &hash)?),
+            // chunks written before the header existed are stored as they are
+            None => codec::decode_chunk(&bytes).or_else(|e| match blake3::hash(&bytes) {
+                raw_hash if raw_hash.as_bytes() == &hash => Ok(bytes.to_vec()),
+                _ => Err(e),
+            }),
+        })
+        .await?
+    }
+
     pub async fn put_chunk(&self, hash: &blake3::Hash, data: Vec<u8>) -> anyhow::Result<()> {
         let path = Self::chunk_path(hash.as_bytes());
-        self.store.put(&path, data.into()).await?;
+        let encoded = self.encode_chunk(hash, data).await?;
+        self.store.put(&path, encoded.into()).await?;
         Ok(())
     }
 
-    pub async fn has_chunk(&self, hash: &blake3::Hash) -> bool {
-        let path = Self::chunk_path(hash.as_bytes());
-        self.store.head(&path).await.is_ok()
+    pub async fn has_chunk(&self, hash: &blake3::Hash) -> anyhow::Result<bool> {
+        if self.probe_chunk(hash).await? {
+            return Ok(true);
+        }
+        self.refresh_packs().await?;
+        Ok(self.packed_chunk(hash).is_some())
+    }
+
+    // Like `has_chunk`, but only knows packs whose indexes were already loaded
+    pub async fn probe_chunk(&self, hash: &blake3::Hash) -> anyhow::Result<bool> {
+        if self.packed_chunk(hash).is_some() {
+            return Ok(true);
+        }
+        match self.store.head(&Self::chunk_path(hash.as_bytes())).await {
+            Ok(_) => Ok(true),
+            Err(object_store::Error::NotFound { .. }) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
     }
 
     pub async fn get_chunk(&self, hash: &blake3::Hash) -> anyhow::Result<Vec<u8>> {
-        let path = Self::chunk_path(hash.as_bytes());
-        let bytes = self.store.get(&path).await?.bytes().await?;
-        Ok(bytes.to_vec())
+        let bytes = match self.packed_chunk(hash) {
+            Some((id, entry)) => self.get_packed_chunk(&id, &entry).await?,
+            None => match self.store.get(&Self::chunk_path(hash.as_bytes())).await {
+                Ok(get_result) => get_result.bytes().await?,
+                // packed by another process since the indexes were loaded
+                Err(object_store::Error::NotFound { .. }) => {
+                    self.refresh_packs().await?;
+                    let (id, entry) = self
+                        .packed_chunk(hash)
+                        .with_context(|| format!("chunk {hash} not found"))?;
+                    self.get_packed_chunk(&id, &entry).await?
+                }
+                Err(e) => return Err(e.into()),
+            },
+        };
+        self.decode_chunk(hash, bytes).await
+    }
+
+    fn packed_chunk(&self, hash: &blake3::Hash) -> Option<(String, PackEntry)> {
+        self.packs
+            .lock()
+            .unwrap()
+            .chunks
+            .get(hash.as_bytes())
+            .cloned()
+    }
+
+    async fn get_packed_chunk(&self, id: &str, entry: &PackEntry) -> anyhow::Result<Bytes> {
+        let path = Path::from_iter([PACK_KEY_PREFIX, id]);
+        Ok(self.store.get_range(&path, entry.range()).await?)
     }
 
     pub async fn delete_chunk(&self, hash: &blake3::Hash) -> anyhow::Result<()> {
@@ -52,38 +433,444 @@ impl Storage {
     }
 
     pub async fn get_root_metadata(&self) -> anyhow::Result<Option<Document>> {
-        match self.store.get(&self.root_key).await {
+        Ok(self.get_root_metadata_for_update().await?.0)
+    }
+
+    // Also returns the version that has to be passed to `put_root_metadata`
+    pub async fn get_root_metadata_for_update(
+        &self,
+    ) -> anyhow::Result<(Option<Document>, RootVersion)> {
+        let get_result = match self.store.get(&self.root_key).await {
+            Err(object_store::Error::NotFound { .. })
+                if self.set_name == DEFAULT_SET && self.migrate_legacy_root().await? =>
+            {
+                self.store.get(&self.root_key).await
+            }
+            result => result,
+        };
+        match get_result {
             Ok(get_result) => {
-                let bytes = get_result.bytes().await?;
-                let decoded: Document =
+                let version = UpdateVersion {
+                    e_tag: get_result.meta.e_tag.clone(),
+                    version: get_result.meta.version.clone(),
+                };
+                let bytes = self.open_object(&get_result.bytes().await?, &self.root_key)?;
+                let decoded = Document::decode(&bytes)?;
+                decoded.validate().with_context(|| {
+                    format!("root document of set {} is invalid", self.set_name)
+                })?;
+                Ok((Some(decoded), RootVersion(Some(version))))
+            }
+            Err(object_store::Error::NotFound { .. }) => Ok((None, RootVersion(None))),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Writes the root only if it is still at `expected`, fails with `RootConflict` otherwise
+    pub async fn put_root_metadata(
+        &self,
+        document: Document,
+        expected: &RootVersion,
+    ) -> anyhow::Result<()> {
+        let bytes = self.seal_object(document.encode()?, &self.root_key);
+        let mode = match &expected.0 {
+            Some(version) => PutMode::Update(version.clone()),
+            None => PutMode::Create,
+        };
+        match self
+            .store
+            .put_opts(&self.root_key, bytes.clone().into(), mode.into())
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(
+                object_store::Error::Precondition { .. }
+                | object_store::Error::AlreadyExists { .. },
+            ) => Err(RootConflict.into()),
+            Err(object_store::Error::NotImplemented) => {
+                self.check_then_put_root(bytes, expected).await
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // For backends without conditional updates, this only narrows the race window
+    async fn check_then_put_root(
+        &self,
+        bytes: Vec<u8>,
+        expected: &RootVersion,
+    ) -> anyhow::Result<()> {
+        static WARNED: AtomicBool = AtomicBool::new(false);
+        if !WARNED.swap(true, std::sync::atomic::Ordering::Relaxed) {
+            warn!(
+                "storage backend has no conditional updates, concurrent writers may lose versions"
+            );
+        }
+        let current = match self.store.head(&self.root_key).await {
+            Ok(meta) => Some(UpdateVersion {
+                e_tag: meta.e_tag,
+                version: meta.version,
+            }),
+            Err(object_store::Error::NotFound { .. }) => None,
+            Err(e) => return Err(e.into()),
+        };
+        if current != expected.0 {
+            return Err(RootConflict.into());
+        }
+        self.store.put(&self.root_key, bytes.into()).await?;
+        Ok(())
+    }
+
+    // The index is written after the pack, so listed indexes always point at complete packs
+    pub async fn put_pack(&self, pack: Pack) -> anyhow::Result<String> {
+        let id = pack.id();
+        let index = bincode::encode_to_vec(&pack.index, bincode::config::standard())?;
+        self.store
+            .put(&Path::from_iter([PACK_KEY_PREFIX, &id]), pack.data.into())
+            .await?;
+        self.store
+            .put(&Path::from_iter([PACK_INDEX_KEY_PREFIX, &id]), index.into())
+            .await?;
+        self.packs.lock().unwrap().insert(&id, Arc::new(pack.index));
+        Ok(id)
+    }
+
+    pub async fn get_pack(&self, id: &str) -> anyhow::Result<Bytes> {
+        let path = Path::from_iter([PACK_KEY_PREFIX, id]);
+        Ok(self.store.get(&path).await?.bytes().await?)
+    }
+
+    // Removes the index first, a crash in between leaves an unindexed pack for the next gc
+    pub async fn delete_pack(&self, id: &str) -> anyhow::Result<()> {
+        for prefix in [PACK_INDEX_KEY_PREFIX, PACK_KEY_PREFIX] {
+            match self.store.delete(&Path::from_iter([prefix, id])).await {
+                Ok(()) | Err(object_store::Error::NotFound { .. }) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        self.packs.lock().unwrap().remove(id);
+        Ok(())
+    }
+
+    pub async fn list_packs(&self) -> anyhow::Result<Vec<PackMeta>> {
+        self.list_pack_objects(PACK_KEY_PREFIX).await
+    }
+
+    async fn list_pack_objects(&self, prefix: &str) -> anyhow::Result<Vec<PackMeta>> {
+        let prefix = Path::from(prefix);
+        let mut packs = Vec::new();
+        let mut list = self.store.list(Some(&prefix));
+        while let Some(meta) = list.next().await {
+            let meta = meta?;
+            if let Some(id) = meta.location.filename() {
+                packs.push(PackMeta {
+                    id: id.to_owned(),
+                    size: meta.size as u64,
+                    last_modified: meta.last_modified,
+                });
+            }
+        }
+        Ok(packs)
+    }
+
+    pub async fn get_pack_index(&self, id: &str) -> anyhow::Result<Arc<PackIndex>> {
+        if let Some(index) = self.packs.lock().unwrap().indexes.get(id) {
+            return Ok(index.clone());
+        }
+        let path = Path::from_iter([PACK_INDEX_KEY_PREFIX, id]);
+        let bytes = self.store.get(&path).await?.bytes().await?;
+        let index: PackIndex = bincode::decode_from_slice(&bytes, bincode::config::standard())?.0;
+        let index = Arc::new(index);
+        self.packs.lock().unwrap().insert(id, index.clone());
+        Ok(index)
+    }
+
+    // Loads indexes of packs written since the last call
+    pub async fn refresh_packs(&self) -> anyhow::Result<()> {
+        for meta in self.list_pack_objects(PACK_INDEX_KEY_PREFIX).await? {
+            self.get_pack_index(&meta.id).await?;
+        }
+        Ok(())
+    }
+
+    // Objects describing the source are encrypted along with the chunks, bound to their key
+    fn seal_object(&self, bytes: Vec<u8>, path: &Path) -> Vec<u8> {
+        match &self.key {
+            Some(key) => key.seal(&bytes, path.as_ref().as_bytes()),
+            None => bytes,
+        }
+    }
+
+    fn open_object(&self, bytes: &[u8], path: &Path) -> anyhow::Result<Vec<u8>> {
+        match &self.key {
+            Some(key) => key.open(bytes, path.as_ref().as_bytes()),
+            None => Ok(bytes.to_vec()),
+        }
+    }
+
+    fn checkpoint_path(&self) -> Path {
+        Path::from_iter([CHECKPOINT_KEY_PREFIX, &self.set_name])
+    }
+
+    pub async fn get_checkpoint(&self) -> anyhow::Result<Option<Checkpoint>> {
+        let path = self.checkpoint_path();
+        match self.store.get(&path).await {
+            Ok(get_result) => {
+                let bytes = self.open_object(&get_result.bytes().await?, &path)?;
+                let checkpoint: Checkpoint =
                     bincode::decode_from_slice(&bytes, bincode::config::standard())?.0;
-                Ok(Some(decoded))
+                checkpoint
+                    .validate()
+                    .with_context(|| format!("checkpoint of set {} is invalid", self.set_name))?;
+                Ok(Some(checkpoint))
             }
             Err(object_store::Error::NotFound { .. }) => Ok(None),
             Err(e) => Err(e.into()),
         }
     }
 
-    pub async fn put_root_metadata(&self, document: Document) -> anyhow::Result<()> {
-        let bytes = bincode::encode_to_vec(&document, bincode::config::standard())?;
-        self.store.put(&self.root_key, bytes.into()).await?;
+    pub async fn put_checkpoint(&self, checkpoint: &Checkpoint) -> anyhow::Result<()> {
+        let path = self.checkpoint_path();
+        let bytes = bincode::encode_to_vec(checkpoint, bincode::config::standard())?;
+        let bytes = self.seal_object(bytes, &path);
+        self.store.put(&path, bytes.into()).await?;
         Ok(())
     }
 
+    pub async fn delete_checkpoint(&self) -> anyhow::Result<()> {
+        match self.store.delete(&self.checkpoint_path()).await {
+            Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Sets with an unfinished backup, their checkpoints keep chunks alive through gc
+    pub async fn list_checkpoints(&self) -> anyhow::Result<Vec<String>> {
+        let prefix = Path::from(CHECKPOINT_KEY_PREFIX);
+        let mut sets = Vec::new();
+        let mut list = self.store.list(Some(&prefix));
+        while let Some(meta) = list.next().await {
+            if let Some(name) = meta?.location.filename() {
+                sets.push(name.to_owned());
+            }
+        }
+        sets.sort();
+        Ok(sets)
+    }
+
+    fn lock_path(holder: &str) -> Path {
+        Path::from_iter([LOCK_KEY_PREFIX, holder])
+    }
+
+    pub async fn put_lock(&self, lock: &LockInfo) -> anyhow::Result<LockVersion> {
+        let bytes = bincode::encode_to_vec(lock, bincode::config::standard())?;
+        let result = self
+            .store
+            .put(&Self::lock_path(lock.holder()), bytes.into())
+            .await?;
+        Ok(LockVersion(UpdateVersion {
+            e_tag: result.e_tag,
+            version: result.version,
+        }))
+    }
+
+    // Rewrites a held lock only if it wasn't broken since `expected`, `None` if it was
+    pub async fn refresh_lock(
+        &self,
+        lock: &LockInfo,
+        expected: &LockVersion,
+    ) -> anyhow::Result<Option<LockVersion>> {
+        let path = Self::lock_path(lock.holder());
+        let bytes = bincode::encode_to_vec(lock, bincode::config::standard())?;
+        let mode = PutMode::Update(expected.0.clone());
+        match self.store.put_opts(&path, bytes.into(), mode.into()).await {
+            Ok(result) => Ok(Some(LockVersion(UpdateVersion {
+                e_tag: result.e_tag,
+                version: result.version,
+            }))),
+            Err(
+                object_store::Error::Precondition { .. } | object_store::Error::NotFound { .. },
+            ) => Ok(None),
+            // without conditional updates, this only narrows the race with break-lock
+            Err(object_store::Error::NotImplemented) => match self.store.head(&path).await {
+                Ok(_) => self.put_lock(lock).await.map(Some),
+                Err(object_store::Error::NotFound { .. }) => Ok(None),
+                Err(e) => Err(e.into()),
+            },
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn list_locks(&self) -> anyhow::Result<Vec<LockInfo>> {
+        let prefix = Path::from(LOCK_KEY_PREFIX);
+        let mut locks = Vec::new();
+        let mut list = self.store.list(Some(&prefix));
+        while let Some(meta) = list.next().await {
+            let location = meta?.location;
+            // the lock may be released between listing and reading it
+            let bytes = match self.store.get(&location).await {
+                Ok(get_result) => get_result.bytes().await?,
+                Err(object_store::Error::NotFound { .. }) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let lock = bincode::decode_from_slice(&bytes, bincode::config::standard())?.0;
+            locks.push(lock);
+        }
+        Ok(locks)
+    }
+
+    pub async fn delete_lock(&self, holder: &str) -> anyhow::Result<()> {
+        match self.store.delete(&Self::lock_path(holder)).await {
+            Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
     pub async fn available_hashes(&self) -> anyhow::Result<Vec<blake3::Hash>> {
-        let mut hashes = Vec::new();
+        Ok(self
+            .list_chunks()
+            .await?
+            .into_iter()
+            .map(|x| x.hash)
+            .collect())
+    }
+
+    pub async fn list_chunks(&self) -> anyhow::Result<Vec<ChunkMeta>> {
+        let mut chunks = Vec::new();
         let mut list = self.store.list(None);
         while let Some(meta) = list.next().await {
-            let path: String = meta?.location.into();
-            if path.starts_with(CHUNK_KEY_PREFIX) {
-                let bytes = BASE64_URL_SAFE_NO_PAD.decode(&path[1..])?;
-                if let Ok(bytes) = bytes.try_into() {
-                    hashes.push(blake3::Hash::from_bytes(bytes));
+            let meta = meta?;
+            let path = meta.location.as_ref();
+            // other objects like `Config` share the prefix letter
+            let hash = path
+                .strip_prefix(CHUNK_KEY_PREFIX)
+                .and_then(|x| BASE64_URL_SAFE_NO_PAD.decode(x).ok())
+                .and_then(|x| <[u8; 32]>::try_from(x).ok());
+            if let Some(hash) = hash {
+                chunks.push(ChunkMeta {
+                    hash: blake3::Hash::from_bytes(hash),
+                    size: meta.size as u64,
+                    last_modified: meta.last_modified,
+                    pack: None,
+                    offset: 0,
+                });
+            }
+        }
+        for meta in self.list_pack_objects(PACK_INDEX_KEY_PREFIX).await? {
+            let index = self.get_pack_index(&meta.id).await?;
+            chunks.extend(index.entries.iter().map(|entry| ChunkMeta {
+                hash: blake3::Hash::from_bytes(entry.hash),
+                size: entry.len.into(),
+                last_modified: meta.last_modified,
+                pack: Some(meta.id.clone()),
+                offset: entry.offset,
+            }));
+        }
+        Ok(chunks)
+    }
+
+    pub async fn get_config(&self) -> anyhow::Result<Option<RepoConfig>> {
+        match self.store.get(&Path::from(CONFIG_KEY)).await {
+            Ok(get_result) => {
+                let bytes = get_result.bytes().await?;
+                let config: RepoConfig =
+                    bincode::decode_from_slice(&bytes, bincode::config::standard())?.0;
+                config
+                    .chunker
+                    .validate()
+                    .context("repository config is invalid")?;
+                Ok(Some(config))
+            }
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Fails if the repository was already initialized
+    pub async fn init_config(&self, config: &RepoConfig) -> anyhow::Result<()> {
+        config.chunker.validate()?;
+        let bytes = bincode::encode_to_vec(config, bincode::config::standard())?;
+        match self
+            .store
+            .put_opts(
+                &Path::from(CONFIG_KEY),
+                bytes.into(),
+                PutMode::Create.into(),
+            )
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(object_store::Error::AlreadyExists { .. }) => {
+                anyhow::bail!("repository is already initialized")
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Creates the generation object on first use
+    pub async fn get_generation(&self) -> anyhow::Result<RepoGeneration> {
+        let path = Path::from(GENERATION_KEY);
+        loop {
+            match self.store.get(&path).await {
+                Ok(get_result) => {
+                    let bytes = get_result.bytes().await?;
+                    return Ok(bincode::decode_from_slice(&bytes, bincode::config::standard())?.0);
                 }
+                Err(object_store::Error::NotFound { .. }) => {}
+                Err(e) => return Err(e.into()),
+            }
+            let mut repo_id = [0; 16];
+            rand::thread_rng().fill_bytes(&mut repo_id);
+            let generation = RepoGeneration {
+                repo_id,
+                generation: 0,
+            };
+            let bytes = bincode::encode_to_vec(generation, bincode::config::standard())?;
+            match self
+                .store
+                .put_opts(&path, bytes.into(), PutMode::Create.into())
+                .await
+            {
+                Ok(_) => return Ok(generation),
+                // created concurrently, use that one
+                Err(object_store::Error::AlreadyExists { .. }) => {}
+                Err(e) => return Err(e.into()),
             }
         }
-        Ok(hashes)
     }
+
+    // Must happen before deleting chunks, only called with the exclusive lock held
+    pub async fn bump_generation(&self) -> anyhow::Result<RepoGeneration> {
+        let mut generation = self.get_generation().await?;
+        generation.generation += 1;
+        let bytes = bincode::encode_to_vec(generation, bincode::config::standard())?;
+        self.store
+            .put(&Path::from(GENERATION_KEY), bytes.into())
+            .await?;
+        Ok(generation)
+    }
+
+    pub async fn get_gc_mark(&self) -> anyhow::Result<Option<GcMark>> {
+        match self.store.get(&Path::from(GC_MARK_KEY)).await {
+            Ok(get_result) => {
+                let bytes = get_result.bytes().await?;
+                Ok(Some(
+                    bincode::decode_from_slice(&bytes, bincode::config::standard())?.0,
+                ))
+            }
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn put_gc_mark(&self, mark: &GcMark) -> anyhow::Result<()> {
+        let bytes = bincode::encode_to_vec(mark, bincode::config::standard())?;
+        self.store
+            .put(&Path::from(GC_MARK_KEY), bytes.into())
+            .await?;
+        Ok(())
+    }
+
     pub async fn delete_chunks<I: IntoIterator<Item = [u8; 32]>>(
         &self,
         hashes: I,
@@ -1,15 +1,36 @@
-use object_store::local::LocalFileSystem;
+use object_store::{local::LocalFileSystem, memory::InMemory, ObjectStore};
 use rand::{thread_rng, RngCore};
 use std::{
     fs,
-    io::{ErrorKind, Read},
+    io::{Read, SeekFrom},
     os::unix::fs::FileExt,
     path::Path,
     sync::Arc,
 };
 use tempfile::tempdir;
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    runtime::Handle,
+};
 
-use crate::{blob::Document, gc, Storage};
+use crate::{
+    blob::{VersionSpec, ZERO_CHUNK},
+    changes::ChangedRegions,
+    check::{check, CheckMode},
+    chunker::Chunker,
+    codec::{Compression, HEADER_LEN},
+    crypto::EncryptionConfig,
+    gc,
+    lock::{LockInfo, LockKind, LOCK_TTL},
+    nbd::{serve_connection, Export, Overlay},
+    read_full,
+    reader::{VersionChunks, VersionReader},
+    retention::RetentionPolicy,
+    sparse::SparseFile,
+    storage::{RepoConfig, RootConflict, RootVersion},
+    BackupOptions, GcOptions, RestoreOptions, Storage,
+};
+use chrono::{DateTime, Duration, Utc};
 
 const BUFFER_SIZE: usize = 64 * 1024;
 
@@ -29,7 +50,7 @@ async fn test_backup_and_restore() -> anyhow::Result<()> {
         backup_dir.path(),
     )?))?;
     crate::backup(storage.clone(), &test_file_path).await?;
-    crate::restore(storage.clone(), &restore_file_path).await?;
+    crate::restore(storage.clone(), &restore_file_path, VersionSpec::LATEST).await?;
     assert_files_same(&test_file_path, &restore_file_path).await?;
     Ok(())
 }
@@ -56,13 +77,19 @@ async fn test_backup_with_gc() -> anyhow::Result<()> {
     write_random_data(file.try_clone()?, 0, 1024 * 1024 * 8).await?;
     crate::backup(storage.clone(), &test_file_path).await?;
     // remove first version for gc to work
-    let doc = storage.get_root_metadata().await?.unwrap();
-    storage
-        .put_root_metadata(Document::new(doc.current().clone()))
-        .await?;
-    gc(storage.clone()).await?;
+    let policy = RetentionPolicy {
+        keep_last: 1,
+        ..Default::default()
+    };
+    crate::prune(storage.clone(), &policy).await?;
+    let chunks_before_gc = storage.available_hashes().await?.len();
+    gc(storage.clone(), &GcOptions::default()).await?;
+    assert_eq!(
+        storage.available_hashes().await?.len(),
+        chunks_before_gc - 16
+    );
 
-    crate::restore(storage.clone(), &restore_file_path).await?;
+    crate::restore(storage.clone(), &restore_file_path, VersionSpec::LATEST).await?;
     assert_files_same(&test_file_path, &restore_file_path).await?;
 
     Ok(())
@@ -91,7 +118,7 @@ async fn test_backup_increasing_size() -> anyhow::Result<()> {
     write_random_data(file.try_clone()?, 0, 1024 * 1024 * 12).await?; // 12MB
 
     crate::backup(storage.clone(), &test_file_path).await?;
-    crate::restore(storage.clone(), &restore_file_path).await?;
+    crate::restore(storage.clone(), &restore_file_path, VersionSpec::LATEST).await?;
     assert_files_same(&test_file_path, &restore_file_path).await?;
 
     Ok(())
@@ -120,30 +147,1812 @@ async fn test_backup_decreasing_size() -> anyhow::Result<()> {
     write_random_data(file.try_clone()?, 0, 1024 * 1024 * 8).await?; // 8MB
 
     crate::backup(storage.clone(), &test_file_path).await?;
-    crate::restore(storage.clone(), &restore_file_path).await?;
+    crate::restore(storage.clone(), &restore_file_path, VersionSpec::LATEST).await?;
     assert_files_same(&test_file_path, &restore_file_path).await?;
 
     Ok(())
 }
 
-async fn write_random_data(
-    file: fs::File,
-    offset: usize,
-    size: usize,
-) -> Result<(), anyhow::Error> {
-    tokio::task::spawn_blocking(move || {
-        let mut rng = thread_rng();
-        let mut buffer = vec![0u8; BUFFER_SIZE]; // 64KB chunks
-        for i in 0..size / BUFFER_SIZE {
-            rng.fill_bytes(&mut buffer);
-            file.write_all_at(&buffer, (offset + i * BUFFER_SIZE) as u64)?;
-        }
-        anyhow::Ok(())
-    })
-    .await??;
+#[tokio::test]
+async fn test_backup_odd_size() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let backup_dir = tempdir()?;
+    let data_dir = tempdir()?;
+
+    let test_file_path = data_dir.path().join("test_file.bin");
+    let file = fs::File::create(&test_file_path)?;
+    write_random_data(file.try_clone()?, 0, 1024 * 1024 * 10 + 1).await?; // 10MB + 1 byte
+
+    let restore_file_path = data_dir.path().join("restored_file.bin");
+
+    let storage = Storage::new(Arc::new(LocalFileSystem::new_with_prefix(
+        backup_dir.path(),
+    )?))?;
+    crate::backup(storage.clone(), &test_file_path).await?;
+    let doc = storage.get_root_metadata().await?.unwrap();
+    assert_eq!(doc.current().size(), 1024 * 1024 * 10 + 1);
+
+    crate::restore(storage.clone(), &restore_file_path, VersionSpec::LATEST).await?;
+    assert_files_same(&test_file_path, &restore_file_path).await?;
+    Ok(())
+}
+
+#[test]
+fn test_legacy_document_decoding() -> anyhow::Result<()> {
+    use crate::blob::Document;
+    let chunk_size = u64::from(crate::chunker::DEFAULT_CHUNK_SIZE);
+    let [a, b, c, x] = [[1u8; 32], [2; 32], [3; 32], [4; 32]];
+    // current version [a, b, c], the one before was [a, x]
+    let current = (vec![a, b, c], 2000i64);
+    let history = vec![(vec![1usize], vec![x], 1000i64)];
+    let legacy = bincode::encode_to_vec((current, history), bincode::config::standard())?;
+
+    let doc = Document::decode(&legacy)?;
+    doc.validate()?;
+    assert_eq!(doc.chunker(), Chunker::DEFAULT);
+    assert_eq!(doc.version_count(), 2);
+    assert_eq!(doc.current().size(), 3 * chunk_size);
+    let old = doc.version(0).unwrap();
+    let chunks: Vec<_> = old.chunks().collect();
+    assert_eq!(chunks, [(a.into(), chunk_size), (x.into(), chunk_size)]);
+
+    // version 1 recorded sizes, only the last chunk of a version may be short
+    let current = (vec![a, b], chunk_size + 100, 2000i64);
+    let history = vec![(vec![1usize], vec![x], chunk_size + 10, 1000i64)];
+    let mut v1 = vec![0xff, 1];
+    bincode::encode_into_std_write((current, history), &mut v1, bincode::config::standard())?;
+    let doc_v1 = Document::decode(&v1)?;
+    doc_v1.validate()?;
+    let chunks: Vec<_> = doc_v1.current().chunks().collect();
+    assert_eq!(chunks, [(a.into(), chunk_size), (b.into(), 100)]);
+    let chunks: Vec<_> = doc_v1.version(0).unwrap().chunks().collect();
+    assert_eq!(chunks, [(a.into(), chunk_size), (x.into(), 10)]);
+
+    // documents written now carry a version
+    let encoded = doc.encode()?;
+    let decoded = Document::decode(&encoded)?;
+    assert_eq!(decoded.current(), doc.current());
+    assert_eq!(decoded.version(0), Some(old));
+    let mut unknown = encoded.clone();
+    unknown[1] = 99;
+    assert!(Document::decode(&unknown).is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_backup_smaller_than_chunk() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let backup_dir = tempdir()?;
+    let data_dir = tempdir()?;
+
+    let test_file_path = data_dir.path().join("test_file.bin");
+    let file = fs::File::create(&test_file_path)?;
+    write_random_data(file.try_clone()?, 0, 1000).await?;
+
+    let restore_file_path = data_dir.path().join("restored_file.bin");
+
+    let storage = Storage::new(Arc::new(LocalFileSystem::new_with_prefix(
+        backup_dir.path(),
+    )?))?;
+    crate::backup(storage.clone(), &test_file_path).await?;
+    let doc = storage.get_root_metadata().await?.unwrap();
+    assert_eq!(doc.current().size(), 1000);
+
+    crate::restore(storage.clone(), &restore_file_path, VersionSpec::LATEST).await?;
+    assert_files_same(&test_file_path, &restore_file_path).await?;
+
+    // shrinking to an odd size must not keep stale bytes from the old version
+    file.set_len(300)?;
+    crate::backup(storage.clone(), &test_file_path).await?;
+    force_restore(storage.clone(), &restore_file_path, VersionSpec::LATEST).await?;
+    assert_files_same(&test_file_path, &restore_file_path).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_restore_old_versions() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let backup_dir = tempdir()?;
+    let data_dir = tempdir()?;
+
+    let test_file_path = data_dir.path().join("test_file.bin");
+    let restore_file_path = data_dir.path().join("restored_file.bin");
+    let storage = Storage::new(Arc::new(LocalFileSystem::new_with_prefix(
+        backup_dir.path(),
+    )?))?;
+
+    // keep a copy of each version, sizes vary so both growing and shrinking are covered
+    let sizes = [1024 * 1024 * 3 + 7, 1024 * 1024 * 5, 1024 * 1024 * 2 + 100];
+    let mut copies = Vec::new();
+    let file = fs::File::create(&test_file_path)?;
+    for (i, size) in sizes.into_iter().enumerate() {
+        file.set_len(size as u64)?;
+        write_random_data(file.try_clone()?, 0, 1024 * 1024).await?; // change first 1MB
+        crate::backup(storage.clone(), &test_file_path).await?;
+        let copy = data_dir.path().join(format!("version_{i}.bin"));
+        fs::copy(&test_file_path, &copy)?;
+        copies.push(copy);
+    }
+
+    for (n, copy) in copies.iter().enumerate() {
+        force_restore(storage.clone(), &restore_file_path, VersionSpec::Number(n)).await?;
+        assert_files_same(copy, &restore_file_path).await?;
+
+        let latest = format!("latest~{}", copies.len() - 1 - n).parse()?;
+        force_restore(storage.clone(), &restore_file_path, latest).await?;
+        assert_files_same(copy, &restore_file_path).await?;
+    }
+
+    let doc = storage.get_root_metadata().await?.unwrap();
+    assert_eq!(doc.version_count(), 3);
+    let time = doc.current().timestamp();
+    force_restore(
+        storage.clone(),
+        &restore_file_path,
+        VersionSpec::Timestamp(time),
+    )
+    .await?;
+    assert_files_same(&copies[2], &restore_file_path).await?;
+
+    assert!(doc.select(&VersionSpec::Number(3)).is_none());
+    assert!(doc.select(&VersionSpec::Latest(3)).is_none());
+    let before_first = VersionSpec::Timestamp(time - chrono::Duration::days(1));
+    assert!(doc.select(&before_first).is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_legacy_root_migration() -> anyhow::Result<()> {
+    use object_store::ObjectStore;
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let data_dir = tempdir()?;
+    let test_file_path = data_dir.path().join("test_file.bin");
+    write_random_data(fs::File::create(&test_file_path)?, 0, 1024 * 1024 * 2).await?; // 2MB
+    let restore_file_path = data_dir.path().join("restored_file.bin");
+
+    let store = Arc::new(object_store::memory::InMemory::new());
+    let storage = Storage::new(store.clone())?;
+    crate::backup(storage.clone(), &test_file_path).await?;
+    // move the root to where it was kept before backup sets
+    let set_key = object_store::path::Path::from("Set/default");
+    let root = store.get(&set_key).await?.bytes().await?;
+    store.put(&"Root".into(), root.into()).await?;
+    store.delete(&set_key).await?;
+
+    // its chunks must not be collected before the root is migrated
+    assert!(gc(storage.clone(), &GcOptions::default()).await.is_err());
+    assert_eq!(storage.list_sets().await?, ["default"]);
+    assert!(storage.get_root_metadata().await?.is_some());
+    assert!(!storage.has_legacy_root().await?);
+    gc(storage.clone(), &GcOptions::default()).await?;
+    crate::restore(storage.clone(), &restore_file_path, VersionSpec::LATEST).await?;
+    assert_files_same(&test_file_path, &restore_file_path).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_backup_sets_share_chunks() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let backup_dir = tempdir()?;
+    let data_dir = tempdir()?;
+
+    let home_path = data_dir.path().join("home.bin");
+    let db_path = data_dir.path().join("db.bin");
+    write_random_data(fs::File::create(&home_path)?, 0, 1024 * 1024 * 4).await?; // 4MB
+    fs::copy(&home_path, &db_path)?;
+    let db_file = fs::OpenOptions::new().write(true).open(&db_path)?;
+    write_random_data(db_file.try_clone()?, 0, 1024 * 1024).await?; // first 1MB differs
+
+    let restore_file_path = data_dir.path().join("restored_file.bin");
+    let storage = Storage::new(Arc::new(LocalFileSystem::new_with_prefix(
+        backup_dir.path(),
+    )?))?;
+    let home = storage.with_set("laptop-home")?;
+    let db = storage.with_set("vm-db")?;
+
+    crate::backup(home.clone(), &home_path).await?;
+    let chunks_after_home = storage.available_hashes().await?.len();
+    crate::backup(db.clone(), &db_path).await?;
+    // only the changed first 1MB is new
+    assert_eq!(
+        storage.available_hashes().await?.len(),
+        chunks_after_home + 2
+    );
+    assert_eq!(storage.list_sets().await?, ["laptop-home", "vm-db"]);
+    assert!(storage.get_root_metadata().await?.is_none());
+
+    // second version of db, the old version must survive gc
+    let db_v0_path = data_dir.path().join("db_v0.bin");
+    fs::copy(&db_path, &db_v0_path)?;
+    write_random_data(db_file.try_clone()?, 1024 * 1024 * 2, 1024 * 1024).await?;
+    crate::backup(db.clone(), &db_path).await?;
+    gc(storage.clone(), &GcOptions::default()).await?;
+
+    crate::restore(home.clone(), &restore_file_path, VersionSpec::LATEST).await?;
+    assert_files_same(&home_path, &restore_file_path).await?;
+    force_restore(db.clone(), &restore_file_path, VersionSpec::LATEST).await?;
+    assert_files_same(&db_path, &restore_file_path).await?;
+    force_restore(db.clone(), &restore_file_path, VersionSpec::Number(0)).await?;
+    assert_files_same(&db_v0_path, &restore_file_path).await?;
+    let doc = db.get_root_metadata().await?.unwrap();
+    assert_eq!(doc.version_count(), 2);
+
+    assert!(storage.with_set("../escape").is_err());
+    assert!(storage.with_set("").is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_restore_baseline_repository() -> anyhow::Result<()> {
+    use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let data_dir = tempdir()?;
+    let restore_file_path = data_dir.path().join("restored_file.bin");
+    let chunk_size = crate::chunker::DEFAULT_CHUNK_SIZE as usize;
+
+    // written the way the first release did: raw chunks, whole chunks only and a root
+    // document without sizes
+    let store = Arc::new(InMemory::new());
+    let mut chunks = vec![vec![0u8; chunk_size]; 4];
+    for chunk in &mut chunks {
+        thread_rng().fill_bytes(chunk);
+    }
+    let mut hashes = Vec::new();
+    for chunk in &chunks {
+        let hash = *blake3::hash(chunk).as_bytes();
+        let key = format!("C{}", BASE64_URL_SAFE_NO_PAD.encode(hash));
+        store.put(&key.into(), chunk.clone().into()).await?;
+        hashes.push(hash);
+    }
+    // version 0 is chunks 0 and 3, version 1 is chunks 0, 1 and 2
+    let current = (hashes[..3].to_vec(), 2000i64);
+    let history = vec![(vec![1usize], vec![hashes[3]], 1000i64)];
+    let root = bincode::encode_to_vec((current, history), bincode::config::standard())?;
+    store.put(&"Root".into(), root.into()).await?;
+
+    let storage = Storage::new(store)?;
+    crate::restore(storage.clone(), &restore_file_path, VersionSpec::LATEST).await?;
+    assert_eq!(fs::read(&restore_file_path)?, chunks[..3].concat());
+    force_restore(storage.clone(), &restore_file_path, VersionSpec::Number(0)).await?;
+    assert_eq!(
+        fs::read(&restore_file_path)?,
+        [&chunks[0][..], &chunks[3][..]].concat()
+    );
+    assert!(check(storage, CheckMode::Full).await?.is_ok());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_prune_keeps_versions_restorable() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let backup_dir = tempdir()?;
+    let data_dir = tempdir()?;
+
+    let test_file_path = data_dir.path().join("test_file.bin");
+    let restore_file_path = data_dir.path().join("restored_file.bin");
+    let storage = Storage::new(Arc::new(LocalFileSystem::new_with_prefix(
+        backup_dir.path(),
+    )?))?;
+
+    let file = fs::File::create(&test_file_path)?;
+    write_random_data(file.try_clone()?, 0, 1024 * 1024 * 4).await?; // 4MB
+    let mut copies = Vec::new();
+    for i in 0..5 {
+        // each version rewrites a different 1MB region
+        write_random_data(file.try_clone()?, (i % 4) * 1024 * 1024, 1024 * 1024).await?;
+        crate::backup(storage.clone(), &test_file_path).await?;
+        let copy = data_dir.path().join(format!("version_{i}.bin"));
+        fs::copy(&test_file_path, &copy)?;
+        copies.push(copy);
+    }
+
+    // drop versions 1 and 3 from the middle of the chain
+    let (doc, root_version) = storage.get_root_metadata_for_update().await?;
+    let mut doc = doc.unwrap();
+    doc.retain_versions(|n| n % 2 == 0);
+    assert_eq!(doc.version_count(), 3);
+    storage.put_root_metadata(doc, &root_version).await?;
+    for (n, i) in [0, 2, 4].into_iter().enumerate() {
+        force_restore(storage.clone(), &restore_file_path, VersionSpec::Number(n)).await?;
+        assert_files_same(&copies[i], &restore_file_path).await?;
+    }
+
+    let policy = RetentionPolicy {
+        keep_last: 2,
+        ..Default::default()
+    };
+    crate::prune(storage.clone(), &policy).await?;
+    gc(storage.clone(), &GcOptions::default()).await?;
+    let doc = storage.get_root_metadata().await?.unwrap();
+    assert_eq!(doc.version_count(), 2);
+    for (n, i) in [2, 4].into_iter().enumerate() {
+        force_restore(storage.clone(), &restore_file_path, VersionSpec::Number(n)).await?;
+        assert_files_same(&copies[i], &restore_file_path).await?;
+    }
+
+    // the only old version retains 1MB, so a smaller budget drops it
+    let policy = RetentionPolicy {
+        max_retained_size: Some(1024 * 1024 - 1),
+        ..Default::default()
+    };
+    crate::prune(storage.clone(), &policy).await?;
+    let doc = storage.get_root_metadata().await?.unwrap();
+    assert_eq!(doc.version_count(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_forget_version() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let backup_dir = tempdir()?;
+    let data_dir = tempdir()?;
+
+    let test_file_path = data_dir.path().join("test_file.bin");
+    let restore_file_path = data_dir.path().join("restored_file.bin");
+    let storage = Storage::new(Arc::new(LocalFileSystem::new_with_prefix(
+        backup_dir.path(),
+    )?))?;
+
+    let file = fs::File::create(&test_file_path)?;
+    let mut copies = Vec::new();
+    for (i, size) in [3, 4, 2, 3].into_iter().enumerate() {
+        file.set_len(size * 1024 * 1024)?;
+        write_random_data(file.try_clone()?, i * 512 * 1024, 1024 * 1024).await?;
+        crate::backup(storage.clone(), &test_file_path).await?;
+        let copy = data_dir.path().join(format!("version_{i}.bin"));
+        fs::copy(&test_file_path, &copy)?;
+        copies.push(copy);
+    }
+
+    // a bad snapshot in the middle
+    crate::forget(storage.clone(), VersionSpec::Number(1)).await?;
+    copies.remove(1);
+    // and the current one, the previous version takes its place
+    crate::forget(storage.clone(), VersionSpec::LATEST).await?;
+    copies.pop();
+
+    let doc = storage.get_root_metadata().await?.unwrap();
+    assert_eq!(doc.version_count(), 2);
+    for (n, copy) in copies.iter().enumerate() {
+        force_restore(storage.clone(), &restore_file_path, VersionSpec::Number(n)).await?;
+        assert_files_same(copy, &restore_file_path).await?;
+    }
+
+    assert!(crate::forget(storage.clone(), VersionSpec::Number(2))
+        .await
+        .is_err());
+    crate::forget(storage.clone(), VersionSpec::Number(0)).await?;
+    assert!(crate::forget(storage.clone(), VersionSpec::LATEST)
+        .await
+        .is_err());
+    force_restore(storage.clone(), &restore_file_path, VersionSpec::LATEST).await?;
+    assert_files_same(&copies[1], &restore_file_path).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_root_update_conflicts() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let backup_dir = tempdir()?;
+    let data_dir = tempdir()?;
+
+    let first_path = data_dir.path().join("first.bin");
+    let second_path = data_dir.path().join("second.bin");
+    write_random_data(fs::File::create(&first_path)?, 0, 1024 * 1024 * 2).await?;
+    write_random_data(fs::File::create(&second_path)?, 0, 1024 * 1024 * 3).await?;
+
+    let stores: [Arc<dyn ObjectStore>; 2] = [
+        Arc::new(InMemory::new()),
+        // no conditional updates, falls back to checking before writing
+        Arc::new(LocalFileSystem::new_with_prefix(backup_dir.path())?),
+    ];
+    for store in stores {
+        let storage = Storage::new(store)?;
+
+        // racing backups both end up in the history
+        let (first, second) = tokio::join!(
+            crate::backup(storage.clone(), &first_path),
+            crate::backup(storage.clone(), &second_path),
+        );
+        first?;
+        second?;
+        let (doc, stale_version) = storage.get_root_metadata_for_update().await?;
+        let doc = doc.unwrap();
+        assert_eq!(doc.version_count(), 2);
+
+        // a writer holding an old version must not overwrite newer history
+        crate::backup(storage.clone(), &first_path).await?;
+        let err = storage
+            .put_root_metadata(doc.clone(), &stale_version)
+            .await
+            .unwrap_err();
+        assert!(err.is::<RootConflict>());
+        let err = storage
+            .put_root_metadata(doc, &RootVersion::default())
+            .await
+            .unwrap_err();
+        assert!(err.is::<RootConflict>());
+        let doc = storage.get_root_metadata().await?.unwrap();
+        assert_eq!(doc.version_count(), 3);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_gc_grace_period() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let backup_dir = tempdir()?;
+    let data_dir = tempdir()?;
+
+    let test_file_path = data_dir.path().join("test_file.bin");
+    let restore_file_path = data_dir.path().join("restored_file.bin");
+    let storage = Storage::new(Arc::new(LocalFileSystem::new_with_prefix(
+        backup_dir.path(),
+    )?))?;
+
+    let file = fs::File::create(&test_file_path)?;
+    write_random_data(file.try_clone()?, 0, 1024 * 1024 * 4).await?; // 4MB
+    crate::backup(storage.clone(), &test_file_path).await?;
+    let v0_path = data_dir.path().join("version_0.bin");
+    fs::copy(&test_file_path, &v0_path)?;
+    write_random_data(file.try_clone()?, 0, 1024 * 1024).await?; // replace first 1MB
+    crate::backup(storage.clone(), &test_file_path).await?;
+    let keep_last = RetentionPolicy {
+        keep_last: 1,
+        ..Default::default()
+    };
+    crate::prune(storage.clone(), &keep_last).await?;
+    let chunk_count = storage.available_hashes().await?.len();
+
+    // first run only marks, repeated runs within the grace period don't delete either
+    let hour = GcOptions {
+        grace_period: Some(Duration::hours(1)),
+        ..Default::default()
+    };
+    gc(storage.clone(), &hour).await?;
+    gc(storage.clone(), &hour).await?;
+    assert_eq!(storage.available_hashes().await?.len(), chunk_count);
+    let mark = storage.get_gc_mark().await?.unwrap();
+    assert_eq!(mark.candidates.len(), 2);
+
+    let no_grace = GcOptions {
+        grace_period: Some(Duration::zero()),
+        ..Default::default()
+    };
+    gc(storage.clone(), &no_grace).await?;
+    assert_eq!(storage.available_hashes().await?.len(), chunk_count - 2);
+    assert!(storage.get_gc_mark().await?.unwrap().candidates.is_empty());
+
+    // a marked chunk that gets referenced again before the next run survives
+    crate::backup(storage.clone(), &v0_path).await?;
+    crate::prune(storage.clone(), &keep_last).await?;
+    gc(storage.clone(), &no_grace).await?;
+    assert_eq!(storage.get_gc_mark().await?.unwrap().candidates.len(), 2);
+    crate::backup(storage.clone(), &test_file_path).await?;
+    gc(storage.clone(), &no_grace).await?;
+    crate::restore(storage.clone(), &restore_file_path, VersionSpec::Number(0)).await?;
+    assert_files_same(&v0_path, &restore_file_path).await?;
+    force_restore(storage.clone(), &restore_file_path, VersionSpec::LATEST).await?;
+    assert_files_same(&test_file_path, &restore_file_path).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_check_finds_missing_and_corrupt_chunks() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let backup_dir = tempdir()?;
+    let data_dir = tempdir()?;
+
+    let test_file_path = data_dir.path().join("test_file.bin");
+    let storage = Storage::new(Arc::new(LocalFileSystem::new_with_prefix(
+        backup_dir.path(),
+    )?))?;
+    let file = fs::File::create(&test_file_path)?;
+    write_random_data(file.try_clone()?, 0, 1024 * 1024 * 3 + 5).await?;
+    crate::backup(storage.clone(), &test_file_path).await?;
+    write_random_data(file.try_clone()?, 0, 1024 * 1024).await?;
+    crate::backup(storage.clone(), &test_file_path).await?;
+
+    let report = check(storage.clone(), CheckMode::Full).await?;
+    assert!(report.is_ok());
+    assert_eq!(report.checked_versions, 2);
+    assert_eq!(report.checked_chunks, 9);
+    assert_eq!(report.downloaded_chunks, 9);
+
+    // a historical chunk disappears, the short last chunk gets corrupted with the same size
+    let doc = storage.get_root_metadata().await?.unwrap();
+    let old_chunk = doc
+        .versions()
+        .next()
+        .unwrap()
+        .unique_chunk_hashes()
+        .next()
+        .unwrap();
+    storage.delete_chunk(&old_chunk).await?;
+    let (last_chunk, last_len) = doc.current().chunks().last().unwrap();
+    assert_eq!(last_len, 5);
+    storage.put_chunk(&last_chunk, vec![0; 5]).await?;
+
+    let report = check(storage.clone(), CheckMode::Fast).await?;
+    assert_eq!(report.missing_chunks, [old_chunk]);
+    assert!(report.corrupt_chunks.is_empty());
+    assert_eq!(report.downloaded_chunks, 0);
+    let report = check(storage.clone(), CheckMode::Sample(1.0)).await?;
+    assert_eq!(report.corrupt_chunks, [last_chunk]);
+    assert!(!report.is_ok());
+    let report = check(storage.clone(), CheckMode::Sample(0.0)).await?;
+    assert_eq!(report.downloaded_chunks, 0);
+
+    // a chunk of the wrong size is caught without downloading
+    storage.put_chunk(&last_chunk, vec![0; 6]).await?;
+    let report = check(storage.clone(), CheckMode::Fast).await?;
+    assert_eq!(report.corrupt_chunks, [last_chunk]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cdc_dedups_shifted_data() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let backup_dir = tempdir()?;
+    let data_dir = tempdir()?;
+
+    let test_file_path = data_dir.path().join("test_file.bin");
+    let restore_file_path = data_dir.path().join("restored_file.bin");
+    let storage = Storage::new(Arc::new(LocalFileSystem::new_with_prefix(
+        backup_dir.path(),
+    )?))?;
+    let cdc = BackupOptions {
+        chunker: Some(Chunker::cdc(64 * 1024)),
+        ..Default::default()
+    };
+
+    let file = fs::File::create(&test_file_path)?;
+    write_random_data(file.try_clone()?, 0, 1024 * 1024 * 8).await?; // 8MB
+    crate::backup_with_options(storage.clone(), &test_file_path, &cdc).await?;
+    let v0_path = data_dir.path().join("version_0.bin");
+    fs::copy(&test_file_path, &v0_path)?;
+    let doc = storage.get_root_metadata().await?.unwrap();
+    assert_eq!(doc.chunker(), Chunker::cdc(64 * 1024));
+    // only the last chunk may be shorter than the minimum
+    let chunk_count = doc.current().chunk_count();
+    assert!(doc
+        .current()
+        .chunks()
+        .take(chunk_count - 1)
+        .all(|(_, len)| (16 * 1024..=256 * 1024).contains(&len)));
+    let chunks_before = storage.available_hashes().await?.len();
+
+    // insert a few bytes near the start, shifting everything after it
+    let mut data = fs::read(&test_file_path)?;
+    data.splice(100_000..100_000, [42; 1000]);
+    fs::write(&test_file_path, &data)?;
+    // the default chunker of later backups is the one of the set
+    crate::backup(storage.clone(), &test_file_path).await?;
+    let new_chunks = storage.available_hashes().await?.len() - chunks_before;
+    assert!(
+        new_chunks <= 3,
+        "{new_chunks} of {chunk_count} chunks changed"
+    );
+
+    crate::restore(storage.clone(), &restore_file_path, VersionSpec::LATEST).await?;
+    assert_files_same(&test_file_path, &restore_file_path).await?;
+    force_restore(storage.clone(), &restore_file_path, VersionSpec::Number(0)).await?;
+    assert_files_same(&v0_path, &restore_file_path).await?;
+    assert!(check(storage.clone(), CheckMode::Full).await?.is_ok());
+
+    // a set can't switch chunking once created
+    let fixed = BackupOptions {
+        chunker: Some(Chunker::DEFAULT),
+        ..Default::default()
+    };
+    assert!(
+        crate::backup_with_options(storage.clone(), &test_file_path, &fixed)
+            .await
+            .is_err()
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_configurable_chunk_size() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let backup_dir = tempdir()?;
+    let data_dir = tempdir()?;
+
+    let test_file_path = data_dir.path().join("test_file.bin");
+    let restore_file_path = data_dir.path().join("restored_file.bin");
+    let storage = Storage::new(Arc::new(LocalFileSystem::new_with_prefix(
+        backup_dir.path(),
+    )?))?;
+    write_random_data(fs::File::create(&test_file_path)?, 0, 1024 * 1024 + 10).await?;
+
+    let config = RepoConfig {
+        chunker: Chunker::Fixed {
+            chunk_size: 64 * 1024,
+        },
+        encryption: None,
+    };
+    storage.init_config(&config).await?;
+    assert!(storage.init_config(&config).await.is_err());
+    assert_eq!(storage.get_config().await?.as_ref(), Some(&config));
+
+    // new sets pick up the repository default
+    crate::backup(storage.clone(), &test_file_path).await?;
+    let doc = storage.get_root_metadata().await?.unwrap();
+    assert_eq!(doc.chunker(), config.chunker);
+    assert_eq!(doc.current().chunk_count(), 17);
+    crate::restore(storage.clone(), &restore_file_path, VersionSpec::LATEST).await?;
+    assert_files_same(&test_file_path, &restore_file_path).await?;
+
+    // or choose their own
+    let big = BackupOptions {
+        chunk_size: Some(4 * 1024 * 1024),
+        ..Default::default()
+    };
+    let big_set = storage.with_set("big")?;
+    crate::backup_with_options(big_set.clone(), &test_file_path, &big).await?;
+    let doc = big_set.get_root_metadata().await?.unwrap();
+    assert_eq!(doc.current().chunk_count(), 1);
+    force_restore(big_set.clone(), &restore_file_path, VersionSpec::LATEST).await?;
+    assert_files_same(&test_file_path, &restore_file_path).await?;
+
+    // existing sets can't be reinterpreted with another size
+    assert!(
+        crate::backup_with_options(storage.clone(), &test_file_path, &big)
+            .await
+            .is_err()
+    );
+    let zero = BackupOptions {
+        chunk_size: Some(0),
+        ..Default::default()
+    };
+    let new_set = storage.with_set("new")?;
+    assert!(crate::backup_with_options(new_set, &test_file_path, &zero)
+        .await
+        .is_err());
     Ok(())
 }
 
+#[tokio::test]
+async fn test_compressed_chunks() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let backup_dir = tempdir()?;
+    let data_dir = tempdir()?;
+
+    let test_file_path = data_dir.path().join("test_file.bin");
+    let restore_file_path = data_dir.path().join("restored_file.bin");
+    let storage = Storage::new(Arc::new(LocalFileSystem::new_with_prefix(
+        backup_dir.path(),
+    )?))?
+    .with_compression(Compression::Zstd { level: 3 });
+
+    // one compressible chunk followed by one random chunk
+    let chunk_size = crate::chunker::DEFAULT_CHUNK_SIZE as usize;
+    let mut data = b"some text that compresses well ".repeat(chunk_size / 31 + 1);
+    data.truncate(chunk_size);
+    fs::write(&test_file_path, &data)?;
+    let file = fs::OpenOptions::new().write(true).open(&test_file_path)?;
+    write_random_data(file, chunk_size, chunk_size).await?;
+
+    crate::backup(storage.clone(), &test_file_path).await?;
+    let mut sizes: Vec<u64> = storage
+        .list_chunks()
+        .await?
+        .iter()
+        .map(|c| c.size)
+        .collect();
+    sizes.sort();
+    assert_eq!(sizes.len(), 2);
+    assert!(sizes[0] < chunk_size as u64 / 10);
+    // incompressible data is stored as is
+    assert_eq!(sizes[1], (chunk_size + HEADER_LEN) as u64);
+
+    // readers don't need to know the compression
+    let plain = storage.clone().with_compression(Compression::None);
+    crate::restore(plain.clone(), &restore_file_path, VersionSpec::LATEST).await?;
+    assert_files_same(&test_file_path, &restore_file_path).await?;
+    assert!(check(plain.clone(), CheckMode::Full).await?.is_ok());
+
+    // a chunk with a damaged header is reported, not a reason to stop checking
+    let mut chunk_paths = Vec::new();
+    for entry in fs::read_dir(backup_dir.path())? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        if name.starts_with('C') && name.len() == 44 {
+            chunk_paths.push((fs::metadata(&path)?.len(), path));
+        }
+    }
+    chunk_paths.sort();
+    let compressed_path = &chunk_paths[0].1;
+    let original = fs::read(compressed_path)?;
+    let mut damaged = original.clone();
+    damaged[0] = 9; // unknown format version
+    fs::write(compressed_path, &damaged)?;
+    let report = check(plain.clone(), CheckMode::Full).await?;
+    assert_eq!(report.corrupt_chunks.len(), 1);
+    assert_eq!(report.downloaded_chunks, 2);
+    fs::write(compressed_path, &original)?;
+
+    // chunks written before the header existed are read as they are
+    let mut rewritten = 0;
+    for entry in fs::read_dir(backup_dir.path())? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        if name.starts_with('C') && name.len() == 44 {
+            let raw = crate::codec::decode_chunk(&fs::read(&path)?)?;
+            fs::write(&path, raw)?;
+            rewritten += 1;
+        }
+    }
+    assert_eq!(rewritten, 2);
+    force_restore(plain.clone(), &restore_file_path, VersionSpec::LATEST).await?;
+    assert_files_same(&test_file_path, &restore_file_path).await?;
+    assert!(check(plain, CheckMode::Full).await?.is_ok());
+
+    assert_eq!(
+        "zstd:19".parse::<Compression>()?,
+        Compression::Zstd { level: 19 }
+    );
+    assert!("zstd:100".parse::<Compression>().is_err());
+    assert!("lz4".parse::<Compression>().is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_encrypted_repository() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let backup_dir = tempdir()?;
+    let data_dir = tempdir()?;
+
+    let test_file_path = data_dir.path().join("test_file.bin");
+    let restore_file_path = data_dir.path().join("restored_file.bin");
+    let storage = Storage::new(Arc::new(LocalFileSystem::new_with_prefix(
+        backup_dir.path(),
+    )?))?;
+    let chunk_size = crate::chunker::DEFAULT_CHUNK_SIZE as usize;
+    let data = b"known plaintext ".repeat(chunk_size / 16);
+    fs::write(&test_file_path, &data)?;
+
+    let (encryption, _) = EncryptionConfig::generate(b"passphrase")?;
+    storage
+        .init_config(&RepoConfig {
+            chunker: Chunker::DEFAULT,
+            encryption: Some(encryption),
+        })
+        .await?;
+    // writing without the key must not leak plaintext
+    assert!(crate::backup(storage.clone(), &test_file_path)
+        .await
+        .is_err());
+    assert!(storage.clone().unlock(None).await.is_err());
+    assert!(storage.clone().unlock(Some(b"wrong")).await.is_err());
+
+    let unlocked = storage.clone().unlock(Some(b"passphrase")).await?;
+    crate::backup(unlocked.clone(), &test_file_path).await?;
+    crate::restore(unlocked.clone(), &restore_file_path, VersionSpec::LATEST).await?;
+    assert_files_same(&test_file_path, &restore_file_path).await?;
+    assert!(check(unlocked.clone(), CheckMode::Full).await?.is_ok());
+
+    // the bucket can't be probed for known content, nor read
+    let chunks = unlocked.available_hashes().await?;
+    assert_eq!(chunks.len(), 1);
+    assert_ne!(chunks[0], blake3::hash(&data));
+    assert!(!storage.has_chunk(&blake3::hash(&data)).await?);
+    for entry in fs::read_dir(backup_dir.path())? {
+        let path = entry?.path();
+        if path.is_file() {
+            let stored = fs::read(&path)?;
+            assert!(!stored.windows(16).any(|x| x == b"known plaintext "));
+        }
+    }
+    assert!(storage.get_root_metadata().await.is_err());
+    assert!(storage.get_chunk(&chunks[0]).await.is_err());
+
+    // ciphertexts are bound to their names
+    let set = unlocked.with_set("other")?;
+    let root = fs::read(backup_dir.path().join("Set/default"))?;
+    fs::write(backup_dir.path().join("Set/other"), root)?;
+    assert!(set.get_root_metadata().await.is_err());
+    fs::remove_file(backup_dir.path().join("Set/other"))?;
+
+    // a chunk that fails authentication is reported as corrupt
+    for entry in fs::read_dir(backup_dir.path())? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        if name.starts_with('C') && name.len() == 44 {
+            let mut stored = fs::read(&path)?;
+            *stored.last_mut().unwrap() ^= 1;
+            fs::write(&path, stored)?;
+        }
+    }
+    let report = check(unlocked, CheckMode::Full).await?;
+    assert_eq!(report.corrupt_chunks, chunks);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_packed_chunks() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let backup_dir = tempdir()?;
+    let data_dir = tempdir()?;
+
+    let test_file_path = data_dir.path().join("test_file.bin");
+    let restore_file_path = data_dir.path().join("restored_file.bin");
+    let storage = Storage::new(Arc::new(LocalFileSystem::new_with_prefix(
+        backup_dir.path(),
+    )?))?
+    .with_pack_size(1024 * 1024);
+    let options = BackupOptions {
+        chunk_size: Some(64 * 1024),
+        ..Default::default()
+    };
+    write_random_data(fs::File::create(&test_file_path)?, 0, 4 * 1024 * 1024).await?;
+    crate::backup_with_options(storage.clone(), &test_file_path, &options).await?;
+
+    let chunks = storage.list_chunks().await?;
+    assert_eq!(chunks.len(), 64);
+    assert!(chunks.iter().all(|x| x.pack.is_some()));
+    assert_eq!(storage.list_packs().await?.len(), 4);
+
+    // a fresh handle has to find the chunks through the pack indexes
+    let reader = Storage::new(Arc::new(LocalFileSystem::new_with_prefix(
+        backup_dir.path(),
+    )?))?;
+    crate::restore(reader.clone(), &restore_file_path, VersionSpec::LATEST).await?;
+    assert_files_same(&test_file_path, &restore_file_path).await?;
+    assert!(reader.has_chunk(&chunks[0].hash).await?);
+
+    // replace most of the data, chunks were packed in upload order so old packs end up
+    // with anything from none to all of their chunks live
+    let file = fs::OpenOptions::new().write(true).open(&test_file_path)?;
+    write_random_data(file, 0, 3 * 1024 * 1024 + 512 * 1024).await?;
+    crate::backup_with_options(storage.clone(), &test_file_path, &options).await?;
+    crate::prune(
+        storage.clone(),
+        &RetentionPolicy {
+            keep_last: 1,
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    gc(storage.clone(), &GcOptions::default()).await?;
+    let sparse_count = storage.list_chunks().await?.len();
+    assert!(sparse_count >= 64);
+    let repack = GcOptions {
+        repack_threshold: Some(1.0),
+        ..Default::default()
+    };
+    gc(storage.clone(), &repack).await?;
+    assert_eq!(storage.list_chunks().await?.len(), 64);
+    assert!(storage.list_packs().await?.len() <= 8);
+
+    force_restore(reader.clone(), &restore_file_path, VersionSpec::LATEST).await?;
+    assert_files_same(&test_file_path, &restore_file_path).await?;
+    assert!(check(reader.clone(), CheckMode::Full).await?.is_ok());
+
+    // the fast check doesn't trust the indexes alone
+    let packs = storage.list_packs().await?;
+    let index = storage.get_pack_index(&packs[0].id).await?;
+    let mut deleted: Vec<_> = index
+        .entries
+        .iter()
+        .map(|x| blake3::Hash::from_bytes(x.hash))
+        .collect();
+    deleted.sort_by_key(|x| *x.as_bytes());
+    let index = storage.get_pack_index(&packs[1].id).await?;
+    let last = index.entries.iter().max_by_key(|x| x.offset).unwrap();
+    let pack_dir = backup_dir.path().join("Pack");
+    fs::remove_file(pack_dir.join(&packs[0].id))?;
+    let pack = fs::OpenOptions::new()
+        .write(true)
+        .open(pack_dir.join(&packs[1].id))?;
+    pack.set_len(packs[1].size - 1)?;
+    let report = check(reader, CheckMode::Fast).await?;
+    assert_eq!(report.missing_chunks, deleted);
+    assert_eq!(report.corrupt_chunks, [blake3::Hash::from_bytes(last.hash)]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_chunk_cache() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let backup_dir = tempdir()?;
+    let data_dir = tempdir()?;
+    let cache_dir = tempdir()?;
+
+    let test_file_path = data_dir.path().join("test_file.bin");
+    let other_file_path = data_dir.path().join("other_file.bin");
+    let restore_file_path = data_dir.path().join("restored_file.bin");
+    let uncached = Storage::new(Arc::new(LocalFileSystem::new_with_prefix(
+        backup_dir.path(),
+    )?))?;
+    let storage = uncached.clone().with_cache_dir(cache_dir.path().to_owned());
+    write_random_data(fs::File::create(&test_file_path)?, 0, 2 * 1024 * 1024).await?;
+    write_random_data(fs::File::create(&other_file_path)?, 0, 1024 * 1024).await?;
+
+    crate::backup(storage.clone(), &test_file_path).await?;
+    assert_eq!(fs::read_dir(cache_dir.path())?.count(), 1);
+
+    // chunks uploaded by another machine are found by probing
+    let other_set = uncached.with_set("other")?;
+    crate::backup(other_set.clone(), &other_file_path).await?;
+    crate::backup(storage.with_set("other")?, &other_file_path).await?;
+    crate::restore(other_set, &restore_file_path, VersionSpec::LATEST).await?;
+    assert_files_same(&other_file_path, &restore_file_path).await?;
+
+    // the cache is trusted as long as no gc ran, so a chunk lost behind its back stays lost
+    let lost = storage.available_hashes().await?[0];
+    storage.delete_chunk(&lost).await?;
+    crate::backup(storage.clone(), &test_file_path).await?;
+    crate::backup(storage.with_set("other")?, &other_file_path).await?;
+    assert_eq!(
+        check(storage.clone(), CheckMode::Fast)
+            .await?
+            .missing_chunks,
+        [lost]
+    );
+
+    // gc on another machine starts a new generation, so the next backup lists the bucket
+    gc(uncached.clone(), &GcOptions::default()).await?;
+    crate::backup(storage.clone(), &test_file_path).await?;
+    crate::backup(storage.with_set("other")?, &other_file_path).await?;
+    assert!(check(storage, CheckMode::Fast).await?.is_ok());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_changed_regions() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let backup_dir = tempdir()?;
+    let data_dir = tempdir()?;
+
+    let test_file_path = data_dir.path().join("test_file.bin");
+    let restore_file_path = data_dir.path().join("restored_file.bin");
+    let storage = Storage::new(Arc::new(LocalFileSystem::new_with_prefix(
+        backup_dir.path(),
+    )?))?;
+    const CHUNK: usize = 64 * 1024;
+    let options = |regions: &str| -> anyhow::Result<BackupOptions> {
+        Ok(BackupOptions {
+            chunk_size: Some(CHUNK as u32),
+            changed_regions: Some(ChangedRegions::parse(regions)?),
+            ..Default::default()
+        })
+    };
+    let file = fs::File::create(&test_file_path)?;
+    write_random_data(file.try_clone()?, 0, 16 * CHUNK + 100).await?;
+    crate::backup_with_options(storage.clone(), &test_file_path, &options("")?).await?;
+
+    // unlisted changes are not read
+    write_random_data(file.try_clone()?, 3 * CHUNK + 10, 10).await?;
+    write_random_data(file.try_clone()?, 10 * CHUNK, 10).await?;
+    let regions = format!("# offset length\n{} 10\n", 3 * CHUNK + 10);
+    crate::backup_with_options(storage.clone(), &test_file_path, &options(&regions)?).await?;
+    crate::restore(storage.clone(), &restore_file_path, VersionSpec::LATEST).await?;
+    let (expected, restored) = (fs::read(&test_file_path)?, fs::read(&restore_file_path)?);
+    assert_eq!(restored[..10 * CHUNK], expected[..10 * CHUNK]);
+    assert_ne!(restored[10 * CHUNK..], expected[10 * CHUNK..]);
+
+    let regions = format!("{} 1", 10 * CHUNK + 5);
+    crate::backup_with_options(storage.clone(), &test_file_path, &options(&regions)?).await?;
+    force_restore(storage.clone(), &restore_file_path, VersionSpec::LATEST).await?;
+    assert_files_same(&test_file_path, &restore_file_path).await?;
+
+    // the old last chunk changes length when the file grows
+    write_random_data(file.try_clone()?, 16 * CHUNK + 100, 100_000).await?;
+    let regions = format!("{} 100000", 16 * CHUNK + 100);
+    crate::backup_with_options(storage.clone(), &test_file_path, &options(&regions)?).await?;
+    force_restore(storage.clone(), &restore_file_path, VersionSpec::LATEST).await?;
+    assert_files_same(&test_file_path, &restore_file_path).await?;
+
+    // a full pass catches what the regions missed
+    write_random_data(file, 0, 10).await?;
+    let full_pass = BackupOptions {
+        full_pass_every: Some(1),
+        ..options("")?
+    };
+    crate::backup_with_options(storage.clone(), &test_file_path, &full_pass).await?;
+    force_restore(storage.clone(), &restore_file_path, VersionSpec::LATEST).await?;
+    assert_files_same(&test_file_path, &restore_file_path).await?;
+
+    assert_eq!(
+        ChangedRegions::from_bitmap(&[0b0000_0110, 0b1000_0000], 4096),
+        ChangedRegions::parse("4096 8192\n61440 4096")?
+    );
+    assert!(ChangedRegions::parse("10").is_err());
+    let cdc = BackupOptions {
+        chunker: Some(Chunker::cdc(64 * 1024)),
+        ..options("")?
+    };
+    let cdc_set = storage.with_set("cdc")?;
+    assert!(crate::backup_with_options(cdc_set, &test_file_path, &cdc)
+        .await
+        .is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_resume_backup() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let backup_dir = tempdir()?;
+    let data_dir = tempdir()?;
+
+    let test_file_path = data_dir.path().join("test_file.bin");
+    let restore_file_path = data_dir.path().join("restored_file.bin");
+    let storage = Storage::new(Arc::new(LocalFileSystem::new_with_prefix(
+        backup_dir.path(),
+    )?))?;
+    let file = fs::File::create(&test_file_path)?;
+    write_random_data(file.try_clone()?, 0, 64 * 1024 * 1024).await?;
+    let options = BackupOptions {
+        chunk_size: Some(64 * 1024),
+        checkpoint_interval: Some(1024 * 1024),
+        ..Default::default()
+    };
+
+    // interrupt the backup once it has written a checkpoint
+    let interrupt = || async {
+        let backup = tokio::spawn({
+            let (storage, path, options) =
+                (storage.clone(), test_file_path.clone(), options.clone());
+            async move { crate::backup_with_options(storage, &path, &options).await }
+        });
+        let checkpoint = loop {
+            if let Some(checkpoint) = storage.get_checkpoint().await? {
+                break checkpoint;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        };
+        backup.abort();
+        assert!(backup.await.unwrap_err().is_cancelled());
+        assert!(checkpoint.blob.size() < 64 * 1024 * 1024);
+        anyhow::Ok(checkpoint)
+    };
+    interrupt().await?;
+    assert!(storage.get_root_metadata().await?.is_none());
+
+    // gc keeps the uploaded prefix around, once the lock of the dead backup is broken
+    let checkpoint = storage.get_checkpoint().await?.unwrap();
+    crate::break_locks(storage.clone(), true).await?;
+    gc(storage.clone(), &GcOptions::default()).await?;
+    for hash in checkpoint.blob.chunk_hashes() {
+        assert!(storage.has_chunk(&hash).await?);
+    }
+
+    let resume = BackupOptions {
+        resume: true,
+        ..options.clone()
+    };
+    crate::backup_with_options(storage.clone(), &test_file_path, &resume).await?;
+    assert!(storage.get_checkpoint().await?.is_none());
+    crate::restore(storage.clone(), &restore_file_path, VersionSpec::LATEST).await?;
+    assert_files_same(&test_file_path, &restore_file_path).await?;
+
+    // a changed source can't be resumed
+    interrupt().await?;
+    write_random_data(file, 0, 10).await?;
+    assert!(
+        crate::backup_with_options(storage.clone(), &test_file_path, &resume)
+            .await
+            .is_err()
+    );
+    crate::backup_with_options(storage.clone(), &test_file_path, &options).await?;
+    force_restore(storage.clone(), &restore_file_path, VersionSpec::LATEST).await?;
+    assert_files_same(&test_file_path, &restore_file_path).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_restore_in_place() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let backup_dir = tempdir()?;
+    let data_dir = tempdir()?;
+
+    let test_file_path = data_dir.path().join("test_file.bin");
+    let v0_path = data_dir.path().join("version_0.bin");
+    let restore_file_path = data_dir.path().join("restored_file.bin");
+    let storage = Storage::new(Arc::new(LocalFileSystem::new_with_prefix(
+        backup_dir.path(),
+    )?))?;
+    let file = fs::File::create(&test_file_path)?;
+    write_random_data(file.try_clone()?, 0, 2 * 1024 * 1024).await?;
+    crate::backup(storage.clone(), &test_file_path).await?;
+    fs::copy(&test_file_path, &v0_path)?;
+    // change the first chunk and grow
+    write_random_data(file.try_clone()?, 0, 1000).await?;
+    write_random_data(file, 2 * 1024 * 1024, 1024 * 1024).await?;
+    crate::backup(storage.clone(), &test_file_path).await?;
+
+    // a mismatching target is rewritten
+    fs::write(&restore_file_path, b"tiny")?;
+    let in_place = RestoreOptions {
+        in_place: true,
+        ..Default::default()
+    };
+    crate::restore_with_options(
+        storage.clone(),
+        &restore_file_path,
+        VersionSpec::LATEST,
+        &in_place,
+    )
+    .await?;
+    assert_files_same(&test_file_path, &restore_file_path).await?;
+
+    // rolling back only fetches the first chunk, and shrinks the target
+    let doc = storage.get_root_metadata().await?.unwrap();
+    let shared = doc.current().chunk_hashes().nth(1).unwrap();
+    storage.delete_chunk(&shared).await?;
+    crate::restore_with_options(
+        storage.clone(),
+        &restore_file_path,
+        VersionSpec::Number(0),
+        &in_place,
+    )
+    .await?;
+    assert_files_same(&v0_path, &restore_file_path).await?;
+    let full_restore_path = data_dir.path().join("full_restore.bin");
+    assert!(
+        crate::restore(storage, &full_restore_path, VersionSpec::Number(0))
+            .await
+            .is_err()
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_restore_reports_missing_chunk() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let backup_dir = tempdir()?;
+    let data_dir = tempdir()?;
+
+    let test_file_path = data_dir.path().join("test_file.bin");
+    let restore_file_path = data_dir.path().join("restored_file.bin");
+    write_random_data(fs::File::create(&test_file_path)?, 0, 1024 * 1024 * 4).await?; // 4MB
+    let storage = Storage::new(Arc::new(LocalFileSystem::new_with_prefix(
+        backup_dir.path(),
+    )?))?;
+    // more chunks than the restore queues, so chunks are still queued when a fetch fails
+    let options = BackupOptions {
+        chunk_size: Some(4096),
+        ..Default::default()
+    };
+    crate::backup_with_options(storage.clone(), &test_file_path, &options).await?;
+    let doc = storage.get_root_metadata().await?.unwrap();
+    let missing = doc.current().chunk_hashes().next().unwrap();
+    storage.delete_chunk(&missing).await?;
+
+    let err = crate::restore(storage.clone(), &restore_file_path, VersionSpec::LATEST)
+        .await
+        .unwrap_err();
+    assert!(format!("{err:#}").contains(&format!("chunk {missing} not found")));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_concurrent_restore() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let data_dir = tempdir()?;
+    let test_file_path = data_dir.path().join("test_file.bin");
+    let restore_file_path = data_dir.path().join("restored_file.bin");
+    let storage = Storage::new(Arc::new(InMemory::new()))?;
+    let file = fs::File::create(&test_file_path)?;
+    write_random_data(file, 0, 6 * 1024 * 1024 + 1234).await?;
+    let cdc = BackupOptions {
+        chunker: Some(Chunker::cdc(64 * 1024)),
+        ..Default::default()
+    };
+    crate::backup_with_options(storage.clone(), &test_file_path, &cdc).await?;
+
+    for concurrency in [1, 3, 64] {
+        let options = RestoreOptions {
+            concurrency: Some(concurrency),
+            force: true,
+            ..Default::default()
+        };
+        crate::restore_with_options(
+            storage.clone(),
+            &restore_file_path,
+            VersionSpec::LATEST,
+            &options,
+        )
+        .await?;
+        assert_files_same(&test_file_path, &restore_file_path).await?;
+    }
+    let zero = RestoreOptions {
+        concurrency: Some(0),
+        force: true,
+        ..Default::default()
+    };
+    assert!(
+        crate::restore_with_options(storage, &restore_file_path, VersionSpec::LATEST, &zero)
+            .await
+            .is_err()
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_restore_requires_force() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let data_dir = tempdir()?;
+    let test_file_path = data_dir.path().join("test_file.bin");
+    let restore_file_path = data_dir.path().join("restored_file.bin");
+    let storage = Storage::new(Arc::new(InMemory::new()))?;
+    let file = fs::File::create(&test_file_path)?;
+    write_random_data(file, 0, 1024 * 1024 + 100).await?;
+    crate::backup(storage.clone(), &test_file_path).await?;
+
+    // a missing or empty output needs no confirmation
+    let options = RestoreOptions::default();
+    let restore = |options: RestoreOptions| {
+        let storage = storage.clone();
+        let path = restore_file_path.clone();
+        async move { crate::restore_with_options(storage, &path, VersionSpec::LATEST, &options).await }
+    };
+    restore(options.clone()).await?;
+    assert_files_same(&test_file_path, &restore_file_path).await?;
+    fs::write(&restore_file_path, b"")?;
+    restore(options.clone()).await?;
+
+    fs::write(&restore_file_path, b"precious")?;
+    assert!(restore(options.clone()).await.is_err());
+    assert!(
+        crate::restore(storage.clone(), &restore_file_path, VersionSpec::LATEST)
+            .await
+            .is_err()
+    );
+    assert_eq!(fs::read(&restore_file_path)?, b"precious");
+    restore(RestoreOptions {
+        force: true,
+        ..options
+    })
+    .await?;
+    assert_files_same(&test_file_path, &restore_file_path).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_zero_chunks() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let data_dir = tempdir()?;
+    let test_file_path = data_dir.path().join("test_file.bin");
+    let restore_file_path = data_dir.path().join("restored_file.bin");
+    let storage = Storage::new(Arc::new(InMemory::new()))?;
+    // zeros, one random chunk, zeros again and a short zero tail
+    let chunk = 512 * 1024;
+    let size = 5 * chunk + 1000;
+    let file = fs::File::create(&test_file_path)?;
+    file.set_len(size as u64)?;
+    write_random_data(file, 2 * chunk, chunk).await?;
+    crate::backup(storage.clone(), &test_file_path).await?;
+
+    let doc = storage.get_root_metadata().await?.unwrap();
+    let zeros = doc
+        .current()
+        .chunks()
+        .filter(|(hash, _)| *hash == ZERO_CHUNK)
+        .count();
+    assert_eq!(zeros, 5);
+    assert_eq!(doc.current().chunk_hashes().count(), 1);
+    assert_eq!(storage.list_chunks().await?.len(), 1);
+    assert!(check(storage.clone(), CheckMode::Full).await?.is_ok());
+    gc(storage.clone(), &GcOptions::default()).await?;
+
+    // a fresh output only gets the data chunk written, zeros are left as holes
+    crate::restore(storage.clone(), &restore_file_path, VersionSpec::LATEST).await?;
+    assert_files_same(&test_file_path, &restore_file_path).await?;
+    let allocated = std::os::unix::fs::MetadataExt::blocks(&fs::metadata(&restore_file_path)?);
+    assert!(allocated * 512 < 2 * chunk as u64);
+
+    // existing data over zero chunks is overwritten without fetching anything
+    let garbage = fs::OpenOptions::new()
+        .write(true)
+        .open(&restore_file_path)?;
+    write_random_data(garbage, 0, size).await?;
+    let in_place = RestoreOptions {
+        in_place: true,
+        ..Default::default()
+    };
+    crate::restore_with_options(
+        storage.clone(),
+        &restore_file_path,
+        VersionSpec::LATEST,
+        &in_place,
+    )
+    .await?;
+    assert_files_same(&test_file_path, &restore_file_path).await?;
+
+    // data written over zeros leaves only zero chunks in the old version
+    let file = fs::OpenOptions::new().write(true).open(&test_file_path)?;
+    write_random_data(file, 0, chunk).await?;
+    crate::backup(storage.clone(), &test_file_path).await?;
+    let doc = storage.get_root_metadata().await?.unwrap();
+    assert_eq!(doc.versions().next().unwrap().retained_size(), 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sparse_source() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let data_dir = tempdir()?;
+    let test_file_path = data_dir.path().join("test_file.bin");
+    let restore_file_path = data_dir.path().join("restored_file.bin");
+    // holes around two small data extents that don't start on chunk boundaries
+    let size = 4 * 1024 * 1024 + 123;
+    let file = fs::File::create(&test_file_path)?;
+    file.set_len(size as u64)?;
+    write_random_data(file.try_clone()?, 1024 * 1024 + 100, 5000).await?;
+    write_random_data(file, size - 700, 700).await?;
+    let expected = fs::read(&test_file_path)?;
+
+    let mut sparse = SparseFile::open(&test_file_path)?;
+    assert_eq!(sparse.size(), size as u64);
+    assert!(sparse.is_hole(0, 1024 * 1024)?);
+    assert!(!sparse.is_hole(1024 * 1024, 512 * 1024)?);
+    assert!(sparse.read_chunk_at(2 * 1024 * 1024, 4096)?.is_none());
+    let mut all = Vec::new();
+    sparse.read_to_end(&mut all)?;
+    assert!(all == expected);
+    let offset = 1024 * 1024 - 10;
+    let data = sparse.read_chunk_at(offset, 20_000)?.unwrap();
+    assert!(data == expected[offset as usize..][..20_000]);
+
+    for chunker in [Chunker::DEFAULT, Chunker::cdc(64 * 1024)] {
+        let storage = Storage::new(Arc::new(InMemory::new()))?;
+        let options = BackupOptions {
+            chunker: Some(chunker),
+            ..Default::default()
+        };
+        crate::backup_with_options(storage.clone(), &test_file_path, &options).await?;
+        force_restore(storage.clone(), &restore_file_path, VersionSpec::LATEST).await?;
+        assert_files_same(&test_file_path, &restore_file_path).await?;
+        let doc = storage.get_root_metadata().await?.unwrap();
+        assert!(doc.current().chunk_hashes().count() < 4);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sparse_restore() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let data_dir = tempdir()?;
+    let test_file_path = data_dir.path().join("test_file.bin");
+    let restore_file_path = data_dir.path().join("restored_file.bin");
+    let storage = Storage::new(Arc::new(InMemory::new()))?;
+    let chunk = 512 * 1024;
+    let file = fs::File::create(&test_file_path)?;
+    file.set_len(8 * chunk as u64)?;
+    write_random_data(file, 3 * chunk, chunk).await?;
+    crate::backup(storage.clone(), &test_file_path).await?;
+    let allocated = |path: &Path| -> anyhow::Result<u64> {
+        Ok(std::os::unix::fs::MetadataExt::blocks(&fs::metadata(path)?) * 512)
+    };
+
+    // restoring in place over a fully allocated file keeps it allocated, unless sparse
+    let mut options = RestoreOptions {
+        in_place: true,
+        ..Default::default()
+    };
+    for sparse in [false, true] {
+        let garbage = fs::File::create(&restore_file_path)?;
+        write_random_data(garbage, 0, 10 * chunk).await?;
+        options.sparse = sparse;
+        crate::restore_with_options(
+            storage.clone(),
+            &restore_file_path,
+            VersionSpec::LATEST,
+            &options,
+        )
+        .await?;
+        assert_files_same(&test_file_path, &restore_file_path).await?;
+        let allocated = allocated(&restore_file_path)?;
+        match sparse {
+            true => assert!(allocated <= 2 * chunk as u64),
+            false => assert!(allocated >= 8 * chunk as u64),
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_version_reader() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let data_dir = tempdir()?;
+    let test_file_path = data_dir.path().join("test_file.bin");
+    let storage = Storage::new(Arc::new(InMemory::new()))?;
+    let chunk = 512 * 1024;
+    let file = fs::File::create(&test_file_path)?;
+    file.set_len(6 * chunk as u64 + 777)?;
+    write_random_data(file, 0, 4 * chunk + 10).await?;
+    crate::backup(storage.clone(), &test_file_path).await?;
+    let expected = fs::read(&test_file_path)?;
+    let blob = storage
+        .get_root_metadata()
+        .await?
+        .unwrap()
+        .current()
+        .clone();
+
+    let mut reader = VersionReader::new(storage.clone(), &blob).with_cache_size(2);
+    assert_eq!(reader.size(), expected.len() as u64);
+    let mut all = Vec::new();
+    reader.read_to_end(&mut all).await?;
+    assert!(all == expected);
+    for (offset, len) in [
+        (chunk - 5, 10),
+        (0, 3),
+        (6 * chunk, 777),
+        (3 * chunk + 1, chunk),
+    ] {
+        let mut buf = vec![0; len];
+        reader.seek(SeekFrom::Start(offset as u64)).await?;
+        reader.read_exact(&mut buf).await?;
+        assert!(buf == expected[offset..][..len]);
+    }
+    let mut buf = vec![0; 100];
+    assert_eq!(
+        reader.read_at(&mut buf, expected.len() as u64 - 40).await?,
+        40
+    );
+
+    // only the chunks covering the requested range are fetched
+    let second = blob.chunk_hashes().nth(1).unwrap();
+    storage.delete_chunk(&second).await?;
+    let mut reader = VersionReader::new(storage.clone(), &blob).with_read_ahead(0);
+    let mut buf = vec![0; 1000];
+    reader.read_at(&mut buf, 2 * chunk as u64 + 5).await?;
+    assert!(buf == expected[2 * chunk + 5..][..1000]);
+    assert!(reader.read_at(&mut buf, chunk as u64).await.is_err());
+
+    // the blocking adapter works from a plain thread
+    let mut reader = VersionReader::new(storage, &blob).into_blocking(Handle::current());
+    let tail = tokio::task::spawn_blocking(move || {
+        let mut tail = Vec::new();
+        std::io::Seek::seek(&mut reader, SeekFrom::End(-1000))?;
+        reader.read_to_end(&mut tail)?;
+        anyhow::Ok(tail)
+    })
+    .await??;
+    assert!(tail == expected[expected.len() - 1000..]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_nbd_export() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let data_dir = tempdir()?;
+    let test_file_path = data_dir.path().join("test_file.bin");
+    let overlay_path = data_dir.path().join("overlay.bin");
+    let storage = Storage::new(Arc::new(InMemory::new()))?;
+    // data, a zero chunk and a short data chunk
+    let chunk = 512 * 1024;
+    let size = 2 * chunk + 1000;
+    let file = fs::File::create(&test_file_path)?;
+    file.set_len(size as u64)?;
+    write_random_data(file.try_clone()?, 0, chunk).await?;
+    write_random_data(file, 2 * chunk, 1000).await?;
+    crate::backup(storage.clone(), &test_file_path).await?;
+    let mut expected = fs::read(&test_file_path)?;
+    let blob = storage
+        .get_root_metadata()
+        .await?
+        .unwrap()
+        .current()
+        .clone();
+    // an existing file is never taken over
+    fs::write(&overlay_path, b"keep")?;
+    assert!(Overlay::create(&overlay_path, size as u64).is_err());
+    assert_eq!(fs::read(&overlay_path)?, b"keep");
+    fs::remove_file(&overlay_path)?;
+    let overlay = Overlay::create(&overlay_path, size as u64)?;
+    let chunks = VersionChunks::new(storage, &blob);
+    let export = Arc::new(Export::new("default".to_owned(), chunks, Some(overlay)));
+    let (mut client, server) = tokio::io::duplex(64 * 1024);
+    let server = tokio::spawn(serve_connection(export.clone(), server));
+
+    async fn option(
+        client: &mut tokio::io::DuplexStream,
+        option: u32,
+        data: &[u8],
+    ) -> anyhow::Result<Vec<(u32, Vec<u8>)>> {
+        client.write_u64(0x4948_4156_454f_5054).await?;
+        client.write_u32(option).await?;
+        client.write_u32(data.len() as u32).await?;
+        client.write_all(data).await?;
+        let mut replies = Vec::new();
+        loop {
+            assert_eq!(client.read_u64().await?, 0x0003_e889_0455_65a9);
+            assert_eq!(client.read_u32().await?, option);
+            let kind = client.read_u32().await?;
+            let mut data = vec![0; client.read_u32().await? as usize];
+            client.read_exact(&mut data).await?;
+            replies.push((kind, data));
+            if kind != 3 && kind != 4 {
+                return Ok(replies);
+            }
+        }
+    }
+    let mut hello = [0; 18];
+    client.read_exact(&mut hello).await?;
+    assert_eq!(&hello[..8], b"NBDMAGIC");
+    client.write_u32(3).await?;
+    assert_eq!(option(&mut client, 8, &[]).await?, [(1, vec![])]);
+    let query = b"base:allocation";
+    let mut meta = [0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, query.len() as u8].to_vec();
+    meta.extend(query);
+    let replies = option(&mut client, 10, &meta).await?;
+    assert_eq!(replies[0].0, 4);
+    assert_eq!(&replies[0].1[4..], query);
+    let replies = option(&mut client, 7, &[0, 0, 0, 0, 0, 0]).await?;
+    assert_eq!(replies[0].1[2..10], (size as u64).to_be_bytes());
+
+    async fn request(
+        client: &mut tokio::io::DuplexStream,
+        kind: u16,
+        cookie: u64,
+        offset: usize,
+        len: usize,
+        data: &[u8],
+    ) -> anyhow::Result<()> {
+        client.write_u32(0x2560_9513).await?;
+        client.write_u16(0).await?;
+        client.write_u16(kind).await?;
+        client.write_u64(cookie).await?;
+        client.write_u64(offset as u64).await?;
+        client.write_u32(len as u32).await?;
+        client.write_all(data).await?;
+        Ok(())
+    }
+    // cookie, reply type and payload of a structured reply
+    async fn reply(client: &mut tokio::io::DuplexStream) -> anyhow::Result<(u64, u16, Vec<u8>)> {
+        assert_eq!(client.read_u32().await?, 0x668e_33ef);
+        assert_eq!(client.read_u16().await?, 1);
+        let kind = client.read_u16().await?;
+        let cookie = client.read_u64().await?;
+        let mut payload = vec![0; client.read_u32().await? as usize];
+        client.read_exact(&mut payload).await?;
+        Ok((cookie, kind, payload))
+    }
+    let status = |payload: &[u8]| -> Vec<(u32, u32)> {
+        payload[4..]
+            .chunks(8)
+            .map(|x| {
+                let len = u32::from_be_bytes(x[..4].try_into().unwrap());
+                (len, u32::from_be_bytes(x[4..].try_into().unwrap()))
+            })
+            .collect()
+    };
+
+    // concurrent reads across chunk boundaries, answered in any order
+    let reads = [
+        (chunk - 100, 200),
+        (0, 4096),
+        (2 * chunk - 10, 1010),
+        (chunk, 100),
+    ];
+    for (cookie, &(offset, len)) in reads.iter().enumerate() {
+        request(&mut client, 0, cookie as u64, offset, len, &[]).await?;
+    }
+    for _ in reads {
+        let (cookie, kind, payload) = reply(&mut client).await?;
+        let (offset, len) = reads[cookie as usize];
+        assert_eq!(kind, 1);
+        assert_eq!(payload[..8], (offset as u64).to_be_bytes());
+        assert!(payload[8..] == expected[offset..][..len]);
+    }
+    request(&mut client, 7, 10, 0, size, &[]).await?;
+    let (_, kind, payload) = reply(&mut client).await?;
+    assert_eq!(kind, 5);
+    let (chunk_len, tail_len) = (chunk as u32, 1000);
+    assert_eq!(
+        status(&payload),
+        [(chunk_len, 0), (chunk_len, 3), (tail_len, 0)]
+    );
+    request(&mut client, 0, 11, size - 10, 20, &[]).await?;
+    assert_eq!(reply(&mut client).await?.1, 0x8001);
+    request(&mut client, 7, 11, chunk, 0, &[]).await?;
+    assert_eq!(reply(&mut client).await?.1, 0x8001);
+
+    // writes land in the overlay, partial blocks keep their surroundings
+    let written = [7u8; 5000];
+    request(&mut client, 1, 12, chunk - 3000, written.len(), &written).await?;
+    assert_eq!(reply(&mut client).await?.1, 0);
+    expected[chunk - 3000..][..5000].copy_from_slice(&written);
+    request(&mut client, 0, 13, chunk - 8192, 16384, &[]).await?;
+    let (_, _, payload) = reply(&mut client).await?;
+    assert!(payload[8..] == expected[chunk - 8192..][..16384]);
+    request(&mut client, 7, 14, chunk, chunk, &[]).await?;
+    let (_, _, payload) = reply(&mut client).await?;
+    assert_eq!(status(&payload), [(chunk_len, 0)]);
+
+    request(&mut client, 2, 15, 0, 0, &[]).await?;
+    server.await??;
+    drop(export);
+    assert!(!overlay_path.exists());
+    Ok(())
+}
+
+#[test]
+fn test_chunk_reader() -> anyhow::Result<()> {
+    let mut data = vec![0u8; 300_000];
+    thread_rng().fill_bytes(&mut data);
+    for chunker in [
+        Chunker::Fixed { chunk_size: 1000 },
+        Chunker::cdc(4096),
+        Chunker::Cdc {
+            min_size: 64,
+            avg_size: 64,
+            max_size: 64,
+        },
+    ] {
+        chunker.validate()?;
+        let mut reader = chunker.reader(&data[..]);
+        let mut joined = Vec::new();
+        while let Some(chunk) = reader.next_chunk()? {
+            assert!(!chunk.is_empty() && chunk.len() <= chunker.max_chunk_size());
+            joined.extend(chunk);
+        }
+        assert_eq!(joined, data);
+    }
+    assert!(Chunker::cdc(1000).validate().is_err());
+    assert!(Chunker::Fixed { chunk_size: 0 }.validate().is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_repository_locks() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let backup_dir = tempdir()?;
+    let data_dir = tempdir()?;
+
+    let test_file_path = data_dir.path().join("test_file.bin");
+    let restore_file_path = data_dir.path().join("restored_file.bin");
+    write_random_data(fs::File::create(&test_file_path)?, 0, 1024 * 1024).await?;
+    let storage = Storage::new(Arc::new(LocalFileSystem::new_with_prefix(
+        backup_dir.path(),
+    )?))?;
+    crate::backup(storage.clone(), &test_file_path).await?;
+    // operations clean up after themselves
+    assert!(storage.list_locks().await?.is_empty());
+
+    // a running gc blocks everything
+    let gc_lock = LockInfo::new(LockKind::Exclusive, "gc", LOCK_TTL);
+    storage.put_lock(&gc_lock).await?;
+    assert!(crate::backup(storage.clone(), &test_file_path)
+        .await
+        .is_err());
+    assert!(
+        crate::restore(storage.clone(), &restore_file_path, VersionSpec::LATEST)
+            .await
+            .is_err()
+    );
+    assert_eq!(storage.list_locks().await?.len(), 1);
+    // not stale yet
+    assert!(crate::break_locks(storage.clone(), false).await?.is_empty());
+    assert_eq!(crate::break_locks(storage.clone(), true).await?.len(), 1);
+
+    // a running backup blocks gc, but not other backups
+    let backup_lock = LockInfo::new(LockKind::Shared, "backup", LOCK_TTL);
+    storage.put_lock(&backup_lock).await?;
+    assert!(gc(storage.clone(), &GcOptions::default()).await.is_err());
+    crate::backup(storage.clone(), &test_file_path).await?;
+    storage.delete_lock(backup_lock.holder()).await?;
+
+    // expired locks from crashed processes are ignored and can be broken
+    let stale_lock = LockInfo::new(LockKind::Exclusive, "gc", -LOCK_TTL);
+    storage.put_lock(&stale_lock).await?;
+    gc(storage.clone(), &GcOptions::default()).await?;
+    let broken = crate::break_locks(storage.clone(), false).await?;
+    assert_eq!(broken.len(), 1);
+    assert_eq!(broken[0].holder(), stale_lock.holder());
+    assert!(storage.list_locks().await?.is_empty());
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_lost_lock_aborts_operation() -> anyhow::Result<()> {
+    tracing_subscriber::fmt().with_test_writer().try_init().ok();
+    let storage = Storage::new(Arc::new(InMemory::new()))?;
+    let operation = async {
+        let locks = storage.list_locks().await?;
+        crate::break_locks(storage.clone(), true).await?;
+        assert_eq!(locks.len(), 1);
+        // would keep deleting chunks forever
+        std::future::pending::<()>().await;
+        anyhow::Ok(())
+    };
+    let result = crate::with_lock(&storage, LockKind::Exclusive, "gc", operation).await;
+    assert!(result.unwrap_err().to_string().contains("lock was lost"));
+    Ok(())
+}
+
+#[test]
+fn test_retention_policy_select() {
+    let start: DateTime<Utc> = "2024-01-01T00:30:00Z".parse().unwrap();
+    // one version every 6 hours for 10 days
+    let timestamps = (0..40)
+        .map(|i| start + Duration::hours(6 * i))
+        .collect::<Vec<_>>();
+    let kept = |policy: RetentionPolicy| {
+        let keep = policy.select(&timestamps);
+        (0..timestamps.len())
+            .filter(|&n| keep[n])
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        kept(RetentionPolicy {
+            keep_last: 3,
+            ..Default::default()
+        }),
+        [37, 38, 39]
+    );
+    // newest version of each of the last 3 days
+    assert_eq!(
+        kept(RetentionPolicy {
+            keep_daily: 3,
+            ..Default::default()
+        }),
+        [31, 35, 39]
+    );
+    // 2024-01-01 is a monday, so weeks start at version 0 and 28
+    assert_eq!(
+        kept(RetentionPolicy {
+            keep_weekly: 5,
+            ..Default::default()
+        }),
+        [27, 39]
+    );
+    assert_eq!(
+        kept(RetentionPolicy {
+            keep_last: 1,
+            keep_monthly: 1,
+            keep_hourly: 2,
+            ..Default::default()
+        }),
+        [38, 39]
+    );
+    // only a size budget keeps everything here, apply() trims afterwards
+    assert_eq!(
+        kept(RetentionPolicy {
+            max_retained_size: Some(0),
+            ..Default::default()
+        })
+        .len(),
+        40
+    );
+    assert!(RetentionPolicy::default().is_empty());
+}
+
+async fn write_random_data(
+    file: fs::File,
+    offset: usize,
+    size: usize,
+) -> Result<(), anyhow::Error> {
+    tokio::task::spawn_blocking(move || {
+        let mut rng = thread_rng();
+        let mut buffer = vec![0u8; BUFFER_SIZE]; // 64KB chunks
+        for start in (0..size).step_by(BUFFER_SIZE) {
+            let len = BUFFER_SIZE.min(size - start);
+            rng.fill_bytes(&mut buffer[..len]);
+            file.write_all_at(&buffer[..len], (offset + start) as u64)?;
+        }
+        anyhow::Ok(())
+    })
+    .await??;
+    Ok(())
+}
+
+// Restores over the output of an earlier restore
+async fn force_restore(storage: Storage, path: &Path, version: VersionSpec) -> anyhow::Result<()> {
+    let options = RestoreOptions {
+        force: true,
+        ..Default::default()
+    };
+    crate::restore_with_options(storage, path, version, &options).await
+}
+
 async fn assert_files_same(
     test_file_path: &Path,
     restore_file_path: &Path,
@@ -153,24 +1962,25 @@ async fn assert_files_same(
     tokio::task::spawn_blocking(move || {
         let mut original = fs::File::open(&test_file_path)?;
         let mut restored = fs::File::open(&restore_file_path)?;
+        assert_eq!(
+            original.metadata()?.len(),
+            restored.metadata()?.len(),
+            "Restored file size doesn't match original"
+        );
         let mut buf1 = vec![0u8; BUFFER_SIZE];
         let mut buf2 = vec![0u8; BUFFER_SIZE];
 
         loop {
-            let r1 = original.read_exact(&mut buf1);
-            let r2 = restored.read_exact(&mut buf2);
-
-            match (r1, r2) {
-                (Err(e1), Err(e2))
-                    if e1.kind() == ErrorKind::UnexpectedEof
-                        && e2.kind() == ErrorKind::UnexpectedEof =>
-                {
-                    return anyhow::Ok(());
-                }
-                (r1, r2) => (r1?, r2?),
-            };
-
-            assert_eq!(&buf1, &buf2, "Restored file doesn't match original");
+            let n1 = read_full(&mut original, &mut buf1)?;
+            let n2 = read_full(&mut restored, &mut buf2)?;
+            assert_eq!(
+                &buf1[..n1],
+                &buf2[..n2],
+                "Restored file doesn't match original"
+            );
+            if n1 < BUFFER_SIZE {
+                return anyhow::Ok(());
+            }
         }
     })
     .await??;